@@ -1,9 +1,18 @@
-extern crate arraydeque;
-
-use std::io::Write;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::mem;
 
 use common::*;
-use value::{ValueOwned, ValueRef};
+use gc::{Gc, Heap};
+use object::{Obj, ObjString};
+use value::{Value, ValueOwned};
+
+// constant indices are at most 24 bits wide, tests lower the limit so that
+// reaching it stays cheap
+#[cfg(not(test))]
+pub const MAX_CONSTANTS: usize = 1 << 24;
+#[cfg(test)]
+pub const MAX_CONSTANTS: usize = 1 << 16;
 
 #[derive(Copy, Clone, Debug)]
 struct Line {
@@ -20,10 +29,7 @@ impl Lines {
         Lines { lines: Vec::new() }
     }
     fn push_line(&mut self, line_no: u64) {
-        let mut line = self
-            .lines
-            .pop()
-            .unwrap_or_else(|| Line { line_no, repeat: 0 });
+        let mut line = self.lines.pop().unwrap_or(Line { line_no, repeat: 0 });
 
         if line_no == line.line_no {
             line.repeat += 1;
@@ -42,21 +48,43 @@ impl Lines {
 
         self.lines
             .iter()
-            .scan(line_acc, |acc, ref line| {
+            .scan(line_acc, |acc, line| {
                 acc.line_no = line.line_no;
                 acc.repeat += line.repeat;
 
                 Some(*acc)
-            }).skip_while(|line_acc| line_acc.repeat < offset + 1)
+            })
+            .skip_while(|line_acc| line_acc.repeat < offset + 1)
             .map(|line_acc| line_acc.line_no)
             .next()
             .unwrap()
     }
 }
 
+// constants that are equal whenever their keys are, numbers compare by bit
+// pattern so 0 and -0 stay distinct
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    String(Gc<ObjString>),
+}
+
+impl ConstantKey {
+    fn of(value: &ValueOwned) -> Option<ConstantKey> {
+        match *value {
+            Value::Number(n) => Some(ConstantKey::Number(n.to_bits())),
+            // strings are interned, equal handles mean equal content
+            Value::Object(Obj::String(s)) => Some(ConstantKey::String(s)),
+            _ => None,
+        }
+    }
+}
+
 pub struct Chunk {
     code: Vec<u8>,
     constants: Vec<ValueOwned>,
+    // index of every number and string in constants
+    constant_indices: HashMap<ConstantKey, usize>,
     lines: Lines,
 }
 
@@ -65,6 +93,7 @@ impl Chunk {
         Chunk {
             code: Vec::new(),
             constants: Vec::new(),
+            constant_indices: HashMap::new(),
             lines: Lines::new(),
         }
     }
@@ -73,23 +102,81 @@ impl Chunk {
         self.lines.push_line(line);
     }
     pub fn write_constant(&mut self, constant: ValueOwned, line: u64) -> usize {
-        self.write(OP_CONSTANT, line);
         let constant_offset = self.add_constant(constant);
-        self.write(constant_offset as u8, line);
+        if constant_offset <= u8::MAX as usize {
+            self.write(OP_CONSTANT, line);
+            self.write(constant_offset as u8, line);
+        } else {
+            self.write(OP_CONSTANT_LONG, line);
+            self.write_long(constant_offset, line);
+        }
         constant_offset
     }
+    // long operands are stored big-endian in three consecutive bytes
+    pub fn write_long(&mut self, value: usize, line: u64) {
+        self.write((value >> 16) as u8, line);
+        self.write((value >> 8) as u8, line);
+        self.write(value as u8, line);
+    }
     pub fn read_byte(&self, offset: usize) -> u8 {
         self.code[offset]
     }
-    pub fn read_constant(&self, offset: usize) -> ValueRef {
+    // jump offsets are stored big-endian in two consecutive bytes
+    pub fn read_short(&self, offset: usize) -> u16 {
+        (u16::from(self.code[offset]) << 8) | u16::from(self.code[offset + 1])
+    }
+    pub fn read_long(&self, offset: usize) -> usize {
+        (usize::from(self.code[offset]) << 16)
+            | (usize::from(self.code[offset + 1]) << 8)
+            | usize::from(self.code[offset + 2])
+    }
+    pub fn patch_short(&mut self, offset: usize, value: u16) {
+        self.code[offset] = (value >> 8) as u8;
+        self.code[offset + 1] = value as u8;
+    }
+    pub fn code_len(&self) -> usize {
+        self.code.len()
+    }
+    // source line of the instruction byte at offset
+    pub fn line_no(&self, offset: usize) -> u64 {
+        self.lines.find_line_no(offset)
+    }
+    pub fn read_constant(&self, offset: usize) -> ValueOwned {
         let constant_offset = self.read_byte(offset);
-        (&self.constants[constant_offset as usize]).into()
+        self.constants[constant_offset as usize].clone()
+    }
+    pub fn read_constant_long(&self, offset: usize) -> ValueOwned {
+        self.constants[self.read_long(offset)].clone()
+    }
+    pub fn constants_len(&self) -> usize {
+        self.constants.len()
     }
+    pub fn constants(&self) -> impl Iterator<Item = &ValueOwned> {
+        self.constants.iter()
+    }
+    // returns the index of an equal constant if there is one
     pub fn add_constant(&mut self, value: ValueOwned) -> usize {
+        let key = ConstantKey::of(&value);
+        if let Some(&index) = key.as_ref().and_then(|key| self.constant_indices.get(key)) {
+            return index;
+        }
+
         self.constants.push(value);
-        self.constants.len() - 1
+        let index = self.constants.len() - 1;
+        if let Some(key) = key {
+            self.constant_indices.insert(key, index);
+        }
+
+        index
+    }
+    // rough number of bytes owned by the chunk
+    pub fn size(&self) -> usize {
+        self.code.len()
+            + self.constants.len() * mem::size_of::<ValueOwned>()
+            + self.constant_indices.len() * mem::size_of::<(ConstantKey, usize)>()
+            + self.lines.lines.len() * mem::size_of::<Line>()
     }
-    pub fn disassemble<W: Write>(&self, write_to: &mut W) {
+    pub fn disassemble<W: Write>(&self, heap: &Heap, write_to: &mut W) -> io::Result<()> {
         let count = self.code.len();
         let mut i: usize = 0;
         loop {
@@ -97,10 +184,17 @@ impl Chunk {
                 break;
             }
 
-            i = self.disassemble_instruction(i, write_to);
+            i = self.disassemble_instruction(i, heap, write_to)?;
         }
+
+        Ok(())
     }
-    fn disassemble_instruction<W: Write>(&self, offset: usize, write_to: &mut W) -> usize {
+    fn disassemble_instruction<W: Write>(
+        &self,
+        offset: usize,
+        heap: &Heap,
+        write_to: &mut W,
+    ) -> io::Result<usize> {
         let prev_line_no = if offset > 0 {
             self.lines.find_line_no(offset - 1)
         } else {
@@ -108,11 +202,36 @@ impl Chunk {
         };
         let line_no = self.lines.find_line_no(offset);
         if offset > 0 && line_no == prev_line_no {
-            write!(write_to, "   | ");
+            write!(write_to, "   | ")?;
         } else {
-            write!(write_to, "{:04} ", line_no);
+            write!(write_to, "{:04} ", line_no)?;
         }
 
+        self.disassemble_opcode(offset, heap, write_to)
+    }
+    // the instruction at offset prefixed with its offset and line, returns
+    // the offset of the next instruction
+    pub fn trace_instruction<W: Write>(
+        &self,
+        offset: usize,
+        heap: &Heap,
+        write_to: &mut W,
+    ) -> io::Result<usize> {
+        write!(
+            write_to,
+            "{:04} {:04} ",
+            offset,
+            self.lines.find_line_no(offset)
+        )?;
+
+        self.disassemble_opcode(offset, heap, write_to)
+    }
+    fn disassemble_opcode<W: Write>(
+        &self,
+        offset: usize,
+        heap: &Heap,
+        write_to: &mut W,
+    ) -> io::Result<usize> {
         let instr = self.code[offset];
 
         match instr {
@@ -120,14 +239,100 @@ impl Chunk {
             OP_PRINT => Self::disassemble_simple_instruction("OP_PRINT", offset, write_to),
             OP_POP => Self::disassemble_simple_instruction("OP_POP", offset, write_to),
             OP_DEFINE_GLOBAL => {
-                self.disassemble_constant_instruct("OP_DEFINE_GLOBAL", offset, write_to)
+                self.disassemble_constant_instruct("OP_DEFINE_GLOBAL", offset, heap, write_to)
+            }
+            OP_SET_GLOBAL => {
+                self.disassemble_constant_instruct("OP_SET_GLOBAL", offset, heap, write_to)
+            }
+            OP_GET_GLOBAL => {
+                self.disassemble_constant_instruct("OP_GET_GLOBAL", offset, heap, write_to)
+            }
+            OP_DEFINE_GLOBAL_LONG => self.disassemble_constant_long_instruct(
+                "OP_DEFINE_GLOBAL_LONG",
+                offset,
+                heap,
+                write_to,
+            ),
+            OP_SET_GLOBAL_LONG => self.disassemble_constant_long_instruct(
+                "OP_SET_GLOBAL_LONG",
+                offset,
+                heap,
+                write_to,
+            ),
+            OP_GET_GLOBAL_LONG => self.disassemble_constant_long_instruct(
+                "OP_GET_GLOBAL_LONG",
+                offset,
+                heap,
+                write_to,
+            ),
+            OP_CLOSURE | OP_CLOSURE_LONG => {
+                self.disassemble_closure_instruction(offset, heap, write_to)
             }
-            OP_SET_GLOBAL => self.disassemble_constant_instruct("OP_SET_GLOBAL", offset, write_to),
-            OP_GET_GLOBAL => self.disassemble_constant_instruct("OP_GET_GLOBAL", offset, write_to),
+            OP_GET_UPVALUE => self.disassemble_byte_instruction("OP_GET_UPVALUE", offset, write_to),
+            OP_SET_UPVALUE => self.disassemble_byte_instruction("OP_SET_UPVALUE", offset, write_to),
+            OP_CLOSE_UPVALUE => {
+                Self::disassemble_simple_instruction("OP_CLOSE_UPVALUE", offset, write_to)
+            }
+            OP_CLASS => self.disassemble_constant_instruct("OP_CLASS", offset, heap, write_to),
+            OP_CLASS_LONG => {
+                self.disassemble_constant_long_instruct("OP_CLASS_LONG", offset, heap, write_to)
+            }
+            OP_GET_PROPERTY => {
+                self.disassemble_constant_instruct("OP_GET_PROPERTY", offset, heap, write_to)
+            }
+            OP_SET_PROPERTY => {
+                self.disassemble_constant_instruct("OP_SET_PROPERTY", offset, heap, write_to)
+            }
+            OP_GET_PROPERTY_LONG => self.disassemble_constant_long_instruct(
+                "OP_GET_PROPERTY_LONG",
+                offset,
+                heap,
+                write_to,
+            ),
+            OP_SET_PROPERTY_LONG => self.disassemble_constant_long_instruct(
+                "OP_SET_PROPERTY_LONG",
+                offset,
+                heap,
+                write_to,
+            ),
+            OP_METHOD => self.disassemble_constant_instruct("OP_METHOD", offset, heap, write_to),
+            OP_METHOD_LONG => {
+                self.disassemble_constant_long_instruct("OP_METHOD_LONG", offset, heap, write_to)
+            }
+            OP_INVOKE => self.disassemble_invoke_instruction("OP_INVOKE", offset, heap, write_to),
+            OP_INVOKE_LONG => {
+                self.disassemble_invoke_instruction("OP_INVOKE_LONG", offset, heap, write_to)
+            }
+            OP_INHERIT => Self::disassemble_simple_instruction("OP_INHERIT", offset, write_to),
+            OP_GET_SUPER => {
+                self.disassemble_constant_instruct("OP_GET_SUPER", offset, heap, write_to)
+            }
+            OP_GET_SUPER_LONG => {
+                self.disassemble_constant_long_instruct("OP_GET_SUPER_LONG", offset, heap, write_to)
+            }
+            OP_SUPER_INVOKE => {
+                self.disassemble_invoke_instruction("OP_SUPER_INVOKE", offset, heap, write_to)
+            }
+            OP_SUPER_INVOKE_LONG => {
+                self.disassemble_invoke_instruction("OP_SUPER_INVOKE_LONG", offset, heap, write_to)
+            }
+            OP_CALL => self.disassemble_byte_instruction("OP_CALL", offset, write_to),
+            OP_GET_LOCAL => self.disassemble_byte_instruction("OP_GET_LOCAL", offset, write_to),
+            OP_SET_LOCAL => self.disassemble_byte_instruction("OP_SET_LOCAL", offset, write_to),
+            OP_JUMP => self.disassemble_jump_instruction("OP_JUMP", true, offset, write_to),
+            OP_JUMP_IF_FALSE => {
+                self.disassemble_jump_instruction("OP_JUMP_IF_FALSE", true, offset, write_to)
+            }
+            OP_LOOP => self.disassemble_jump_instruction("OP_LOOP", false, offset, write_to),
             OP_NIL => Self::disassemble_simple_instruction("OP_NIL", offset, write_to),
             OP_TRUE => Self::disassemble_simple_instruction("OP_TRUE", offset, write_to),
             OP_FALSE => Self::disassemble_simple_instruction("OP_FALSE", offset, write_to),
-            OP_CONSTANT => self.disassemble_constant_instruct("OP_CONSTANT", offset, write_to),
+            OP_CONSTANT => {
+                self.disassemble_constant_instruct("OP_CONSTANT", offset, heap, write_to)
+            }
+            OP_CONSTANT_LONG => {
+                self.disassemble_constant_long_instruct("OP_CONSTANT_LONG", offset, heap, write_to)
+            }
             OP_NEGATE => Self::disassemble_simple_instruction("OP_NEGATE", offset, write_to),
             OP_ADD => Self::disassemble_simple_instruction("OP_ADD", offset, write_to),
             OP_SUBTRACT => Self::disassemble_simple_instruction("OP_SUBTRACT", offset, write_to),
@@ -137,9 +342,15 @@ impl Chunk {
             OP_EQUAL => Self::disassemble_simple_instruction("OP_EQUAL", offset, write_to),
             OP_GREATER => Self::disassemble_simple_instruction("OP_GREATER", offset, write_to),
             OP_LESS => Self::disassemble_simple_instruction("OP_LESS", offset, write_to),
+            OP_GREATER_EQUAL => {
+                Self::disassemble_simple_instruction("OP_GREATER_EQUAL", offset, write_to)
+            }
+            OP_LESS_EQUAL => {
+                Self::disassemble_simple_instruction("OP_LESS_EQUAL", offset, write_to)
+            }
             _ => {
-                writeln!(write_to, "Unknown OptCode {}", instr);
-                offset + 1
+                writeln!(write_to, "Unknown OptCode {}", instr)?;
+                Ok(offset + 1)
             }
         }
     }
@@ -148,21 +359,172 @@ impl Chunk {
         name: &str,
         offset: usize,
         write_to: &mut W,
-    ) -> usize {
-        writeln!(write_to, "{}", name);
-        offset + 1
+    ) -> io::Result<usize> {
+        writeln!(write_to, "{}", name)?;
+        Ok(offset + 1)
+    }
+    fn disassemble_byte_instruction<W: Write>(
+        &self,
+        name: &str,
+        offset: usize,
+        write_to: &mut W,
+    ) -> io::Result<usize> {
+        let slot: u8 = self.code[offset + 1];
+
+        writeln!(write_to, "{:<16} {:04}", name, slot)?;
+
+        Ok(offset + 2)
+    }
+    fn disassemble_jump_instruction<W: Write>(
+        &self,
+        name: &str,
+        forward: bool,
+        offset: usize,
+        write_to: &mut W,
+    ) -> io::Result<usize> {
+        let jump = self.read_short(offset + 1) as usize;
+        let next = offset + 3;
+        let target = if forward { next + jump } else { next - jump };
+
+        writeln!(write_to, "{:<16} {:04} -> {:04}", name, offset, target)?;
+
+        Ok(next)
+    }
+    fn disassemble_closure_instruction<W: Write>(
+        &self,
+        offset: usize,
+        heap: &Heap,
+        write_to: &mut W,
+    ) -> io::Result<usize> {
+        let name = if is_long_form(self.code[offset]) {
+            "OP_CLOSURE_LONG"
+        } else {
+            "OP_CLOSURE"
+        };
+        let (constant, mut offset) = self.constant_operand(offset);
+        let function = &self.constants[constant];
+
+        writeln!(
+            write_to,
+            "{:<16} {:04} {}",
+            name,
+            constant,
+            function.display(heap)
+        )?;
+
+        let upvalue_count = match function {
+            Value::Object(Obj::Function(function)) => heap.get(*function).upvalue_count,
+            _ => 0,
+        };
+
+        for _ in 0..upvalue_count {
+            let is_local = self.code[offset];
+            let index = self.code[offset + 1];
+            writeln!(
+                write_to,
+                "   | {:<16} {:04} {}",
+                "",
+                index,
+                if is_local == 1 { "local" } else { "upvalue" }
+            )?;
+            offset += 2;
+        }
+
+        Ok(offset)
+    }
+    fn disassemble_invoke_instruction<W: Write>(
+        &self,
+        name: &str,
+        offset: usize,
+        heap: &Heap,
+        write_to: &mut W,
+    ) -> io::Result<usize> {
+        let (constant, offset) = self.constant_operand(offset);
+        let arg_count: u8 = self.code[offset];
+
+        write!(
+            write_to,
+            "{:<16} ({} args) {:04} ",
+            name, arg_count, constant
+        )?;
+        writeln!(write_to, "{}", self.constants[constant].display(heap))?;
+
+        Ok(offset + 1)
     }
     fn disassemble_constant_instruct<W: Write>(
         &self,
         name: &str,
         offset: usize,
+        heap: &Heap,
         write_to: &mut W,
-    ) -> usize {
+    ) -> io::Result<usize> {
         let constant: u8 = self.code[offset + 1];
 
-        write!(write_to, "{:<16} {:04} ", name, constant);
-        writeln!(write_to, "{}", self.constants[constant as usize]);
+        write!(write_to, "{:<16} {:04} ", name, constant)?;
+        writeln!(
+            write_to,
+            "{}",
+            self.constants[constant as usize].display(heap)
+        )?;
+
+        Ok(offset + 2)
+    }
+    // constant index of the instruction at offset and the offset following
+    // the index
+    fn constant_operand(&self, offset: usize) -> (usize, usize) {
+        if is_long_form(self.code[offset]) {
+            (self.read_long(offset + 1), offset + 4)
+        } else {
+            (usize::from(self.code[offset + 1]), offset + 2)
+        }
+    }
+    fn disassemble_constant_long_instruct<W: Write>(
+        &self,
+        name: &str,
+        offset: usize,
+        heap: &Heap,
+        write_to: &mut W,
+    ) -> io::Result<usize> {
+        let constant = self.read_long(offset + 1);
+
+        write!(write_to, "{:<16} {:04} ", name, constant)?;
+        writeln!(write_to, "{}", self.constants[constant].display(heap))?;
+
+        Ok(offset + 4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object::ObjFunction;
+
+    #[test]
+    fn add_constant_deduplicates_numbers_and_strings() {
+        let mut heap = Heap::new();
+        let mut chunk = Chunk::new();
+
+        let one = chunk.add_constant(Value::from(1.0));
+        assert_eq!(chunk.add_constant(Value::from(1.0)), one);
+        // equal as numbers but not as bit patterns
+        assert_ne!(
+            chunk.add_constant(Value::from(0.0)),
+            chunk.add_constant(Value::from(-0.0))
+        );
+
+        let name = chunk.add_constant(Value::Object(Obj::String(heap.intern("name"))));
+        let same = chunk.add_constant(Value::Object(Obj::String(heap.intern("name"))));
+        assert_eq!(name, same);
+
+        // functions are never shared
+        let a = chunk.add_constant(Value::Object(Obj::Function(
+            heap.alloc(ObjFunction::new(None)),
+        )));
+        let b = chunk.add_constant(Value::Object(Obj::Function(
+            heap.alloc(ObjFunction::new(None)),
+        )));
+        assert_ne!(a, b);
 
-        offset + 2
+        assert_eq!(chunk.constants_len(), 6);
     }
 }
@@ -20,3 +20,65 @@ pub const OP_DEFINE_GLOBAL: u8 = 0x10;
 pub const OP_SET_GLOBAL: u8 = 0x11;
 
 pub const OP_PRINT: u8 = 0x12;
+
+pub const OP_GET_LOCAL: u8 = 0x13;
+pub const OP_SET_LOCAL: u8 = 0x14;
+
+pub const OP_JUMP: u8 = 0x15;
+pub const OP_JUMP_IF_FALSE: u8 = 0x16;
+pub const OP_LOOP: u8 = 0x17;
+
+pub const OP_CALL: u8 = 0x18;
+
+pub const OP_CLOSURE: u8 = 0x19;
+pub const OP_GET_UPVALUE: u8 = 0x1a;
+pub const OP_SET_UPVALUE: u8 = 0x1b;
+pub const OP_CLOSE_UPVALUE: u8 = 0x1c;
+
+pub const OP_CLASS: u8 = 0x1d;
+pub const OP_GET_PROPERTY: u8 = 0x1e;
+pub const OP_SET_PROPERTY: u8 = 0x1f;
+
+pub const OP_INVOKE: u8 = 0x20;
+pub const OP_METHOD: u8 = 0x21;
+
+pub const OP_INHERIT: u8 = 0x22;
+pub const OP_GET_SUPER: u8 = 0x23;
+pub const OP_SUPER_INVOKE: u8 = 0x24;
+
+// same as their short forms with a 24 bit constant index
+pub const OP_CONSTANT_LONG: u8 = 0x25;
+pub const OP_GET_GLOBAL_LONG: u8 = 0x26;
+pub const OP_DEFINE_GLOBAL_LONG: u8 = 0x27;
+pub const OP_SET_GLOBAL_LONG: u8 = 0x28;
+pub const OP_CLOSURE_LONG: u8 = 0x29;
+pub const OP_CLASS_LONG: u8 = 0x2a;
+pub const OP_GET_PROPERTY_LONG: u8 = 0x2b;
+pub const OP_SET_PROPERTY_LONG: u8 = 0x2c;
+pub const OP_METHOD_LONG: u8 = 0x2d;
+pub const OP_INVOKE_LONG: u8 = 0x2e;
+pub const OP_GET_SUPER_LONG: u8 = 0x2f;
+pub const OP_SUPER_INVOKE_LONG: u8 = 0x30;
+
+// not compiled to the negated opposite comparison, which would be true for NaN
+pub const OP_GREATER_EQUAL: u8 = 0x31;
+pub const OP_LESS_EQUAL: u8 = 0x32;
+
+// whether the constant index operand of the instruction takes three bytes
+pub fn is_long_form(op: u8) -> bool {
+    matches!(
+        op,
+        OP_CONSTANT_LONG
+            | OP_GET_GLOBAL_LONG
+            | OP_DEFINE_GLOBAL_LONG
+            | OP_SET_GLOBAL_LONG
+            | OP_CLOSURE_LONG
+            | OP_CLASS_LONG
+            | OP_GET_PROPERTY_LONG
+            | OP_SET_PROPERTY_LONG
+            | OP_METHOD_LONG
+            | OP_INVOKE_LONG
+            | OP_GET_SUPER_LONG
+            | OP_SUPER_INVOKE_LONG
+    )
+}
@@ -1,15 +1,21 @@
-use chunk::Chunk;
+use chunk::{Chunk, MAX_CONSTANTS};
 use common::*;
-use object::*;
-use scanner::{Scanner, Token, TokenType};
+use gc::{Gc, Heap};
+use object::{Obj, ObjFunction};
+use scanner::{Scanner, Span, Token, TokenType};
+use std::fmt::{self, Write};
 use std::mem;
 use value::{Value, ValueOwned};
 
 #[derive(Debug)]
 pub struct CompileError {
     pub line_no: u64,
+    pub column: u64,
+    pub span: Span,
     pub payload: CompileErrorPayload,
     pub message: Option<String>,
+    // hint on how to fix the error, if there is a common fix
+    help: Option<&'static str>,
 }
 #[derive(Debug)]
 pub enum CompileErrorPayload {
@@ -19,26 +25,172 @@ pub enum CompileErrorPayload {
     TooManyConstants,
 }
 
-pub type Result<T> = ::std::result::Result<T, CompileError>;
+// ansi styles used when rendering for a terminal
+const STYLE_ERROR: &str = "1;31";
+const STYLE_GUTTER: &str = "1;34";
 
-pub fn compile<'a>(source: &'a str, chunk: &mut Chunk) -> Result<()> {
-    let scanner = Scanner::new(source);
-    let mut parser = Parser::new(scanner, chunk);
+impl CompileError {
+    fn at(token: &Token, payload: CompileErrorPayload, message: Option<String>) -> Self {
+        CompileError {
+            line_no: token.line,
+            column: token.column,
+            span: token.span,
+            payload,
+            message,
+            help: None,
+        }
+    }
+    fn with_help(mut self, help: &'static str) -> Self {
+        self.help = Some(help);
+        self
+    }
 
-    if parser.match_ty(TokenType::TOKEN_EOF) {
-        return Ok(());
+    pub fn code(&self) -> &'static str {
+        match self.payload {
+            CompileErrorPayload::ScannerError => "E001",
+            CompileErrorPayload::ParserError => "E002",
+            CompileErrorPayload::UnexpectedToken(_) => "E003",
+            CompileErrorPayload::TooManyConstants => "E004",
+        }
     }
 
-    loop {
-        parser.declaration()?;
-        if parser.match_ty(TokenType::TOKEN_EOF) {
-            break;
+    pub fn message(&self) -> String {
+        match (&self.message, &self.payload) {
+            (Some(message), _) => message.clone(),
+            (None, CompileErrorPayload::UnexpectedToken(ty)) => format!("Unexpected {:?}", ty),
+            (None, _) => "Invalid syntax".to_string(),
         }
     }
 
-    parser.consume(TokenType::TOKEN_EOF)?;
+    pub fn help(&self) -> Option<&'static str> {
+        self.help
+    }
+
+    // the error with the source line it occurred on and the offending span
+    // underlined, color adds ansi styles for terminals
+    pub fn render(&self, source: &str, color: bool) -> String {
+        let paint = |style: &str, text: &str| {
+            if color {
+                format!("\x1b[{}m{}\x1b[0m", style, text)
+            } else {
+                text.to_string()
+            }
+        };
+
+        let line_no = self.line_no.max(1);
+        let column = self.column.max(1);
+        let line = source.lines().nth(line_no as usize - 1).unwrap_or("");
+
+        // keep tabs so that the underline lines up with the source line
+        let indent: String = line
+            .chars()
+            .take(column as usize - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = source
+            .get(self.span.start..self.span.end)
+            .and_then(|spanned| spanned.lines().next())
+            .map_or(0, |spanned| spanned.chars().count())
+            .max(1);
+
+        let number = line_no.to_string();
+        let gutter = " ".repeat(number.len());
+        let bar = paint(STYLE_GUTTER, "|");
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{}: {}",
+            paint(STYLE_ERROR, &format!("error[{}]", self.code())),
+            self.message()
+        );
+        let _ = writeln!(
+            out,
+            "{}{} line {}, column {}",
+            gutter,
+            paint(STYLE_GUTTER, "-->"),
+            line_no,
+            column
+        );
+        let _ = writeln!(out, "{} {}", gutter, bar);
+        let _ = writeln!(out, "{} {} {}", paint(STYLE_GUTTER, &number), bar, line);
+        let _ = writeln!(
+            out,
+            "{} {} {}{}",
+            gutter,
+            bar,
+            indent,
+            paint(STYLE_ERROR, &"^".repeat(width))
+        );
+        if let Some(help) = self.help() {
+            let _ = writeln!(
+                out,
+                "{} {} help: {}",
+                gutter,
+                paint(STYLE_GUTTER, "="),
+                help
+            );
+        }
+
+        out
+    }
+}
 
-    Ok(())
+// single line form for output that is not a terminal
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[line {}:{}] error[{}]: {}",
+            self.line_no,
+            self.column,
+            self.code(),
+            self.message()
+        )
+    }
+}
+
+impl ::std::error::Error for CompileError {}
+
+pub type Result<T> = ::std::result::Result<T, CompileError>;
+
+// compiles top level code into an implicit function taking no arguments,
+// parsing continues after an error so that all of them are reported
+pub fn compile(
+    source: &str,
+    heap: &mut Heap,
+) -> ::std::result::Result<Gc<ObjFunction>, Vec<CompileError>> {
+    compile_source(source, heap, false)
+}
+
+// like compile, but top level expression statements print their value and
+// the last one may omit its semicolon
+pub fn compile_repl(
+    source: &str,
+    heap: &mut Heap,
+) -> ::std::result::Result<Gc<ObjFunction>, Vec<CompileError>> {
+    compile_source(source, heap, true)
+}
+
+fn compile_source(
+    source: &str,
+    heap: &mut Heap,
+    repl: bool,
+) -> ::std::result::Result<Gc<ObjFunction>, Vec<CompileError>> {
+    let scanner = Scanner::new(source);
+    let mut parser = Parser::new(scanner, heap);
+
+    while !parser.match_ty(TokenType::TOKEN_EOF) {
+        parser.echo = repl;
+        parser.declaration();
+    }
+
+    let function = parser.end_compiler();
+    if !parser.errors.is_empty() {
+        return Err(parser.errors);
+    }
+
+    Ok(parser.heap.alloc(function))
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -75,59 +227,456 @@ impl Precedence {
 
 use self::Precedence::*;
 
-type ParseFn<'a, 'b> = for<'r> fn(&'r mut Parser<'a, 'b>, bool) -> Result<()>;
+type ParseFn<'a> = for<'r> fn(&'r mut Parser<'a>, bool) -> Result<()>;
 
-struct ParseRule<'a, 'b> {
-    prefix: Option<ParseFn<'a, 'b>>,
-    infix: Option<ParseFn<'a, 'b>>,
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
     precedence: Precedence,
 }
 
-pub struct Parser<'a, 'b> {
+// local variables are addressed by a single byte stack slot
+const MAX_LOCALS: usize = 256;
+// argument count is encoded in a single byte operand of OP_CALL
+const MAX_ARITY: usize = 255;
+// upvalues are addressed by a single byte index
+const MAX_UPVALUES: usize = 256;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+// one per class declaration being compiled, innermost last
+struct ClassCompiler {
+    has_superclass: bool,
+}
+
+struct Local<'a> {
+    name: Token<'a>,
+    // None while the variable's initializer is being compiled
+    depth: Option<usize>,
+    // captured locals are moved off the stack when they go out of scope
+    is_captured: bool,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct Upvalue {
+    // local slot of the enclosing function if is_local, otherwise index
+    // into the enclosing function's upvalues
+    index: u8,
+    is_local: bool,
+}
+
+// one per function being compiled, enclosing points to the compiler of
+// the surrounding function
+struct Compiler<'a> {
+    enclosing: Option<Box<Compiler<'a>>>,
+    function: ObjFunction,
+    function_type: FunctionType,
+    locals: Vec<Local<'a>>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(function_type: FunctionType, name: Option<&str>) -> Self {
+        // stack slot 0 holds the function being called, or the receiver
+        // for methods so that it can be accessed as this
+        let reserved_name = match function_type {
+            FunctionType::Initializer | FunctionType::Method => "this",
+            FunctionType::Function | FunctionType::Script => "",
+        };
+        let reserved = Local {
+            name: Token::synthetic(reserved_name, 0),
+            depth: Some(0),
+            is_captured: false,
+        };
+
+        Compiler {
+            enclosing: None,
+            function: ObjFunction::new(name),
+            function_type,
+            locals: vec![reserved],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+        }
+    }
+
+    // stack slot of a local variable of this function
+    fn resolve_local(
+        &self,
+        name: &Token<'a>,
+    ) -> ::std::result::Result<Option<usize>, &'static str> {
+        let found = self
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.identifier_equal(name))
+            .map(|(slot, local)| (slot, local.depth));
+
+        match found {
+            Some((_, None)) => Err("Cannot read local variable in its own initializer"),
+            Some((slot, Some(_))) => Ok(Some(slot)),
+            None => Ok(None),
+        }
+    }
+
+    // upvalue index of a variable declared in an enclosing function,
+    // threading it through every function in between
+    fn resolve_upvalue(
+        &mut self,
+        name: &Token<'a>,
+    ) -> ::std::result::Result<Option<usize>, &'static str> {
+        let upvalue = match self.enclosing {
+            Some(ref mut enclosing) => match enclosing.resolve_local(name)? {
+                Some(slot) => {
+                    enclosing.locals[slot].is_captured = true;
+                    Upvalue {
+                        index: slot as u8,
+                        is_local: true,
+                    }
+                }
+                None => match enclosing.resolve_upvalue(name)? {
+                    Some(index) => Upvalue {
+                        index: index as u8,
+                        is_local: false,
+                    },
+                    None => return Ok(None),
+                },
+            },
+            None => return Ok(None),
+        };
+
+        self.add_upvalue(upvalue).map(Some)
+    }
+
+    // number of functions this one is nested in
+    fn depth(&self) -> usize {
+        match self.enclosing {
+            Some(ref enclosing) => enclosing.depth() + 1,
+            None => 0,
+        }
+    }
+
+    fn add_upvalue(&mut self, upvalue: Upvalue) -> ::std::result::Result<usize, &'static str> {
+        if let Some(index) = self.upvalues.iter().position(|&u| u == upvalue) {
+            return Ok(index);
+        }
+
+        if self.upvalues.len() >= MAX_UPVALUES {
+            return Err("Too many closure variables in function");
+        }
+
+        self.upvalues.push(upvalue);
+        self.function.upvalue_count = self.upvalues.len();
+
+        Ok(self.upvalues.len() - 1)
+    }
+}
+
+pub struct Parser<'a> {
     previous: Token<'a>,
     current: Token<'a>,
     scanner: Scanner<'a>,
-    chunk: &'b mut Chunk,
+    compiler: Compiler<'a>,
+    classes: Vec<ClassCompiler>,
+    // every object created while compiling is allocated here
+    heap: &'a mut Heap,
+    errors: Vec<CompileError>,
+    // set after an error until the parser resynchronizes, errors reported
+    // meanwhile are most likely caused by the first one and are dropped
+    panic_mode: bool,
+    // the declaration about to be parsed is a top level one of a repl
+    // entry, taken by it so that statements nested in it are not echoed
+    echo: bool,
 }
 
-impl<'a, 'b: 'a> Parser<'a, 'b> {
-    pub fn new(mut scanner: Scanner<'a>, chunk: &'b mut Chunk) -> Self {
-        let previous = Token {
-            ty: TokenType::TOKEN_EOF,
-            raw: "",
-            line: 0,
-        };
-        let current = scanner.scan_token();
-        Parser {
-            previous,
-            current,
+impl<'a> Parser<'a> {
+    pub fn new(scanner: Scanner<'a>, heap: &'a mut Heap) -> Self {
+        let eof = Token::new(TokenType::TOKEN_EOF, "", Span::default(), 1, 1);
+        let mut parser = Parser {
+            previous: eof,
+            current: eof,
             scanner,
-            chunk,
-        }
+            compiler: Compiler::new(FunctionType::Script, None),
+            classes: Vec::new(),
+            heap,
+            errors: Vec::new(),
+            panic_mode: false,
+            echo: false,
+        };
+        parser.advance();
+
+        parser
     }
 
-    fn declaration(&mut self) -> Result<()> {
-        if self.match_ty(TokenType::TOKEN_VAR) {
+    fn declaration(&mut self) {
+        let compiler_depth = self.compiler.depth();
+        let class_depth = self.classes.len();
+        let scope_depth = self.compiler.scope_depth;
+        let echo = mem::replace(&mut self.echo, false);
+
+        let result = if self.match_ty(TokenType::TOKEN_CLASS) {
+            self.class_declaration()
+        } else if self.match_ty(TokenType::TOKEN_FUN) {
+            self.fun_declaration()
+        } else if self.match_ty(TokenType::TOKEN_VAR) {
             self.var_declaration()
         } else {
+            self.echo = echo;
             self.statement()
+        };
+
+        if let Err(error) = result {
+            self.report(error);
+        }
+
+        if self.panic_mode {
+            // an error may have left functions, classes and scopes open,
+            // none of the bytecode is run so only the nesting matters
+            while self.compiler.depth() > compiler_depth {
+                self.end_compiler();
+            }
+            self.classes.truncate(class_depth);
+            while self.compiler.scope_depth > scope_depth {
+                self.end_scope();
+            }
+
+            self.synchronize();
+        }
+    }
+
+    // skips tokens until one that likely starts a new statement
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.current.ty != TokenType::TOKEN_EOF {
+            if self.previous.ty == TokenType::TOKEN_SEMICOLON {
+                return;
+            }
+
+            match self.current.ty {
+                TokenType::TOKEN_CLASS
+                | TokenType::TOKEN_FUN
+                | TokenType::TOKEN_VAR
+                | TokenType::TOKEN_FOR
+                | TokenType::TOKEN_IF
+                | TokenType::TOKEN_WHILE
+                | TokenType::TOKEN_PRINT
+                | TokenType::TOKEN_RETURN => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn report(&mut self, error: CompileError) {
+        if self.panic_mode {
+            return;
         }
 
-        // if self.panic_mode {
-        //    self.syncronize()
-        // }
+        self.panic_mode = true;
+        self.errors.push(error);
     }
 
     fn statement(&mut self) -> Result<()> {
+        let echo = mem::replace(&mut self.echo, false);
+
         if self.match_ty(TokenType::TOKEN_PRINT) {
             self.print_statement()
+        } else if self.match_ty(TokenType::TOKEN_IF) {
+            self.if_statement()
+        } else if self.match_ty(TokenType::TOKEN_RETURN) {
+            self.return_statement()
+        } else if self.match_ty(TokenType::TOKEN_WHILE) {
+            self.while_statement()
+        } else if self.match_ty(TokenType::TOKEN_FOR) {
+            self.begin_scope();
+            let result = self.for_statement();
+            self.end_scope();
+            result
+        } else if self.match_ty(TokenType::TOKEN_LEFT_BRACE) {
+            self.begin_scope();
+            let result = self.block();
+            self.end_scope();
+            result
         } else {
-            self.expression_statement()
+            self.expression_statement(echo)
+        }
+    }
+
+    fn block(&mut self) -> Result<()> {
+        while !self.check_ty(TokenType::TOKEN_RIGHT_BRACE) && !self.check_ty(TokenType::TOKEN_EOF) {
+            self.declaration();
+        }
+
+        self.consume_with_error_message(TokenType::TOKEN_RIGHT_BRACE, "Expect } after block")
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.compiler.scope_depth -= 1;
+
+        let scope_depth = self.compiler.scope_depth;
+        while let Some(local) = self.compiler.locals.pop() {
+            if local.depth.is_some_and(|depth| depth <= scope_depth) {
+                self.compiler.locals.push(local);
+                break;
+            }
+
+            if local.is_captured {
+                self.emit_byte(OP_CLOSE_UPVALUE);
+            } else {
+                self.emit_byte(OP_POP);
+            }
+        }
+    }
+
+    fn class_declaration(&mut self) -> Result<()> {
+        self.consume_with_error_message(TokenType::TOKEN_IDENTIFIER, "Expect class name")?;
+        let name = self.previous;
+        let name_constant = self.identifier_constant(&name)?;
+        if self.compiler.scope_depth > 0 {
+            self.declare_variable()?;
+        }
+
+        self.emit_operand((OP_CLASS, Some(OP_CLASS_LONG)), name_constant)?;
+        self.define_variable(name_constant)?;
+
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.match_ty(TokenType::TOKEN_LESS) {
+            self.consume_with_error_message(TokenType::TOKEN_IDENTIFIER, "Expect superclass name")?;
+            self.variable(false)?;
+
+            if name.identifier_equal(&self.previous) {
+                return Err(self.error("A class cannot inherit from itself"));
+            }
+
+            // methods capture the superclass through a local named super,
+            // scoped to the class body so that sibling classes don't share it
+            self.begin_scope();
+            self.add_local(Token::synthetic("super", name.line))?;
+            self.define_variable(0)?;
+
+            self.named_variable(name, false)?;
+            self.emit_byte(OP_INHERIT);
+
+            if let Some(class) = self.classes.last_mut() {
+                class.has_superclass = true;
+            }
+        }
+
+        // load the class so that methods can be bound to it
+        self.named_variable(name, false)?;
+        self.consume_with_error_message(TokenType::TOKEN_LEFT_BRACE, "Expect { before class body")?;
+        while !self.check_ty(TokenType::TOKEN_RIGHT_BRACE) && !self.check_ty(TokenType::TOKEN_EOF) {
+            self.method()?;
+        }
+        self.consume_with_error_message(TokenType::TOKEN_RIGHT_BRACE, "Expect } after class body")?;
+        self.emit_byte(OP_POP);
+
+        let class = self.classes.pop();
+        if class.is_some_and(|class| class.has_superclass) {
+            self.end_scope();
+        }
+
+        Ok(())
+    }
+
+    fn method(&mut self) -> Result<()> {
+        self.consume_with_error_message(TokenType::TOKEN_IDENTIFIER, "Expect method name")?;
+        let name = self.previous;
+        let name_constant = self.identifier_constant(&name)?;
+
+        let function_type = if name.raw == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type)?;
+
+        self.emit_operand((OP_METHOD, Some(OP_METHOD_LONG)), name_constant)
+    }
+
+    fn fun_declaration(&mut self) -> Result<()> {
+        let global = self.parse_variable("Expect function name")?;
+        // a function may refer to itself in its body
+        self.mark_initialized();
+        self.function(FunctionType::Function)?;
+        self.define_variable(global)
+    }
+
+    fn function(&mut self, function_type: FunctionType) -> Result<()> {
+        let name = self.previous.raw;
+        let compiler = Compiler::new(function_type, Some(name));
+        let enclosing = mem::replace(&mut self.compiler, compiler);
+        self.compiler.enclosing = Some(Box::new(enclosing));
+
+        // no end_scope, the whole frame is discarded on return
+        self.begin_scope();
+
+        self.consume_with_error_message(
+            TokenType::TOKEN_LEFT_PAREN,
+            "Expect ( after function name",
+        )?;
+        if !self.check_ty(TokenType::TOKEN_RIGHT_PAREN) {
+            loop {
+                self.compiler.function.arity += 1;
+                if self.compiler.function.arity > MAX_ARITY {
+                    return Err(self.error_at_current("Cannot have more than 255 parameters"));
+                }
+
+                let param = self.parse_variable("Expect parameter name")?;
+                self.define_variable(param)?;
+
+                if !self.match_ty(TokenType::TOKEN_COMMA) {
+                    break;
+                }
+            }
+        }
+        self.consume_with_error_message(TokenType::TOKEN_RIGHT_PAREN, "Expect ) after parameters")?;
+
+        self.consume_with_error_message(
+            TokenType::TOKEN_LEFT_BRACE,
+            "Expect { before function body",
+        )?;
+        self.block()?;
+
+        let upvalues = mem::take(&mut self.compiler.upvalues);
+        let function = self.end_compiler();
+        let value = Value::Object(Obj::Function(self.heap.alloc(function)));
+        let offset = self.make_constant(value)?;
+        self.emit_operand((OP_CLOSURE, Some(OP_CLOSURE_LONG)), offset)?;
+
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index);
+        }
+
+        Ok(())
+    }
+
+    // finishes the function being compiled and resumes its enclosing one
+    fn end_compiler(&mut self) -> ObjFunction {
+        self.emit_return();
+
+        match self.compiler.enclosing.take() {
+            Some(enclosing) => mem::replace(&mut self.compiler, *enclosing).function,
+            None => mem::replace(&mut self.compiler.function, ObjFunction::new(None)),
         }
     }
 
     fn var_declaration(&mut self) -> Result<()> {
-        let offset = self.parse_variable("Expect global variable")?;
+        let offset = self.parse_variable("Expect variable name")?;
 
         if self.match_ty(TokenType::TOKEN_EQUAL) {
             self.expression()?;
@@ -143,13 +692,145 @@ impl<'a, 'b: 'a> Parser<'a, 'b> {
         self.define_variable(offset)
     }
 
-    fn expression_statement(&mut self) -> Result<()> {
+    // echoed statements print their value instead of discarding it, the
+    // last one of the entry may omit its semicolon
+    fn expression_statement(&mut self, echo: bool) -> Result<()> {
         self.expression()?;
-        self.emit_byte(OP_POP);
+
+        if echo {
+            self.emit_byte(OP_PRINT);
+            if self.check_ty(TokenType::TOKEN_EOF) {
+                return Ok(());
+            }
+        } else {
+            self.emit_byte(OP_POP);
+        }
 
         self.consume_with_error_message(TokenType::TOKEN_SEMICOLON, "Expect ; after expression")
     }
 
+    fn if_statement(&mut self) -> Result<()> {
+        self.consume_with_error_message(TokenType::TOKEN_LEFT_PAREN, "Expect ( after if")?;
+        self.expression()?;
+        self.consume_with_error_message(TokenType::TOKEN_RIGHT_PAREN, "Expect ) after condition")?;
+
+        let then_jump = self.emit_jump(OP_JUMP_IF_FALSE);
+        self.emit_byte(OP_POP);
+        self.statement()?;
+
+        let else_jump = self.emit_jump(OP_JUMP);
+
+        self.patch_jump(then_jump)?;
+        self.emit_byte(OP_POP);
+
+        if self.match_ty(TokenType::TOKEN_ELSE) {
+            self.statement()?;
+        }
+
+        self.patch_jump(else_jump)
+    }
+
+    fn while_statement(&mut self) -> Result<()> {
+        let loop_start = self.current_chunk().code_len();
+
+        self.consume_with_error_message(TokenType::TOKEN_LEFT_PAREN, "Expect ( after while")?;
+        self.expression()?;
+        self.consume_with_error_message(TokenType::TOKEN_RIGHT_PAREN, "Expect ) after condition")?;
+
+        let exit_jump = self.emit_jump(OP_JUMP_IF_FALSE);
+        self.emit_byte(OP_POP);
+        self.statement()?;
+
+        self.emit_loop(loop_start)?;
+
+        self.patch_jump(exit_jump)?;
+        self.emit_byte(OP_POP);
+
+        Ok(())
+    }
+
+    // caller wraps this in a scope so that the initializer variable is
+    // local to the loop
+    fn for_statement(&mut self) -> Result<()> {
+        self.consume_with_error_message(TokenType::TOKEN_LEFT_PAREN, "Expect ( after for")?;
+
+        if self.match_ty(TokenType::TOKEN_SEMICOLON) {
+            // no initializer
+        } else if self.match_ty(TokenType::TOKEN_VAR) {
+            self.var_declaration()?;
+        } else {
+            self.expression_statement(false)?;
+        }
+
+        let mut loop_start = self.current_chunk().code_len();
+
+        let exit_jump = if self.match_ty(TokenType::TOKEN_SEMICOLON) {
+            None
+        } else {
+            self.expression()?;
+            self.consume_with_error_message(
+                TokenType::TOKEN_SEMICOLON,
+                "Expect ; after loop condition",
+            )?;
+
+            let exit_jump = self.emit_jump(OP_JUMP_IF_FALSE);
+            self.emit_byte(OP_POP);
+            Some(exit_jump)
+        };
+
+        if !self.match_ty(TokenType::TOKEN_RIGHT_PAREN) {
+            // the increment runs after the body, so jump over it here and
+            // loop back to it at the end of the body
+            let body_jump = self.emit_jump(OP_JUMP);
+
+            let increment_start = self.current_chunk().code_len();
+            self.expression()?;
+            self.emit_byte(OP_POP);
+            self.consume_with_error_message(
+                TokenType::TOKEN_RIGHT_PAREN,
+                "Expect ) after for clauses",
+            )?;
+
+            self.emit_loop(loop_start)?;
+            loop_start = increment_start;
+            self.patch_jump(body_jump)?;
+        }
+
+        self.statement()?;
+
+        self.emit_loop(loop_start)?;
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump)?;
+            self.emit_byte(OP_POP);
+        }
+
+        Ok(())
+    }
+
+    fn return_statement(&mut self) -> Result<()> {
+        if self.compiler.function_type == FunctionType::Script {
+            return Err(self.error("Cannot return from top-level code"));
+        }
+
+        if self.match_ty(TokenType::TOKEN_SEMICOLON) {
+            self.emit_return();
+            Ok(())
+        } else {
+            if self.compiler.function_type == FunctionType::Initializer {
+                return Err(self.error("Cannot return a value from an initializer"));
+            }
+
+            self.expression()?;
+            self.consume_with_error_message(
+                TokenType::TOKEN_SEMICOLON,
+                "Expect ; after return value",
+            )?;
+            self.emit_byte(OP_RETURN);
+            Ok(())
+        }
+    }
+
     fn print_statement(&mut self) -> Result<()> {
         self.expression()?;
         self.consume_with_error_message(
@@ -197,19 +878,127 @@ impl<'a, 'b: 'a> Parser<'a, 'b> {
                 self.emit_byte(OP_GREATER);
             }
             TokenType::TOKEN_GREATER_EQUAL => {
-                self.emit_bytes(OP_LESS, OP_NOT);
+                self.emit_byte(OP_GREATER_EQUAL);
             }
             TokenType::TOKEN_LESS => {
                 self.emit_byte(OP_LESS);
             }
             TokenType::TOKEN_LESS_EQUAL => {
-                self.emit_bytes(OP_GREATER, OP_NOT);
+                self.emit_byte(OP_LESS_EQUAL);
             }
             _ => unreachable!(),
         }
 
         Ok(())
     }
+    fn call(&mut self, _can_assign: bool) -> Result<()> {
+        let arg_count = self.argument_list()?;
+        self.emit_bytes(OP_CALL, arg_count);
+        Ok(())
+    }
+    fn argument_list(&mut self) -> Result<u8> {
+        let mut arg_count: usize = 0;
+
+        if !self.check_ty(TokenType::TOKEN_RIGHT_PAREN) {
+            loop {
+                self.expression()?;
+                arg_count += 1;
+                if arg_count > MAX_ARITY {
+                    return Err(self.error("Cannot have more than 255 arguments"));
+                }
+
+                if !self.match_ty(TokenType::TOKEN_COMMA) {
+                    break;
+                }
+            }
+        }
+
+        self.consume_with_error_message(TokenType::TOKEN_RIGHT_PAREN, "Expect ) after arguments")?;
+
+        Ok(arg_count as u8)
+    }
+    fn dot(&mut self, can_assign: bool) -> Result<()> {
+        self.consume_with_error_message(
+            TokenType::TOKEN_IDENTIFIER,
+            "Expect property name after .",
+        )?;
+        let previous = self.previous;
+        let name = self.identifier_constant(&previous)?;
+
+        if can_assign && self.match_ty(TokenType::TOKEN_EQUAL) {
+            self.expression()?;
+            self.emit_operand((OP_SET_PROPERTY, Some(OP_SET_PROPERTY_LONG)), name)
+        } else if self.match_ty(TokenType::TOKEN_LEFT_PAREN) {
+            // calling a method right away skips creating a bound method
+            let arg_count = self.argument_list()?;
+            self.emit_operand((OP_INVOKE, Some(OP_INVOKE_LONG)), name)?;
+            self.emit_byte(arg_count);
+            Ok(())
+        } else {
+            self.emit_operand((OP_GET_PROPERTY, Some(OP_GET_PROPERTY_LONG)), name)
+        }
+    }
+    fn super_(&mut self, _can_assign: bool) -> Result<()> {
+        match self.classes.last() {
+            None => return Err(self.error("Cannot use super outside of a class")),
+            Some(class) if !class.has_superclass => {
+                return Err(self.error("Cannot use super in a class with no superclass"));
+            }
+            _ => {}
+        }
+
+        self.consume_with_error_message(TokenType::TOKEN_DOT, "Expect . after super")?;
+        self.consume_with_error_message(
+            TokenType::TOKEN_IDENTIFIER,
+            "Expect superclass method name",
+        )?;
+        let previous = self.previous;
+        let name = self.identifier_constant(&previous)?;
+
+        let line = previous.line;
+        self.named_variable(Token::synthetic("this", line), false)?;
+
+        if self.match_ty(TokenType::TOKEN_LEFT_PAREN) {
+            let arg_count = self.argument_list()?;
+            self.named_variable(Token::synthetic("super", line), false)?;
+            self.emit_operand((OP_SUPER_INVOKE, Some(OP_SUPER_INVOKE_LONG)), name)?;
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable(Token::synthetic("super", line), false)?;
+            self.emit_operand((OP_GET_SUPER, Some(OP_GET_SUPER_LONG)), name)?;
+        }
+
+        Ok(())
+    }
+    fn this(&mut self, _can_assign: bool) -> Result<()> {
+        if self.classes.is_empty() {
+            return Err(self.error("Cannot use this outside of a class"));
+        }
+
+        self.variable(false)
+    }
+    // left operand is on the stack, if it's falsy it is the result and the
+    // right operand is skipped
+    fn and(&mut self, _can_assign: bool) -> Result<()> {
+        let end_jump = self.emit_jump(OP_JUMP_IF_FALSE);
+
+        self.emit_byte(OP_POP);
+        self.parse_precedence(PREC_AND)?;
+
+        self.patch_jump(end_jump)
+    }
+    // left operand is on the stack, if it's truthy it is the result and the
+    // right operand is skipped
+    fn or(&mut self, _can_assign: bool) -> Result<()> {
+        let else_jump = self.emit_jump(OP_JUMP_IF_FALSE);
+        let end_jump = self.emit_jump(OP_JUMP);
+
+        self.patch_jump(else_jump)?;
+        self.emit_byte(OP_POP);
+        self.parse_precedence(PREC_OR)?;
+
+        self.patch_jump(end_jump)
+    }
     fn unary(&mut self, _can_assign: bool) -> Result<()> {
         let op_type = self.previous.ty;
 
@@ -229,17 +1018,20 @@ impl<'a, 'b: 'a> Parser<'a, 'b> {
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) -> Result<()> {
-        self.advance()?;
+        self.advance();
 
         let ParseRule { prefix, .. } = Self::get_rule(self.previous.ty);
 
-        let prefix: ParseFn<'a, 'b> = prefix.ok_or_else(|| self.error("Expect expression"))?;
+        let prefix: ParseFn<'a> = prefix.ok_or_else(|| {
+            self.error("Expect expression")
+                .with_help("a value, variable or call was expected here")
+        })?;
         let can_assign = precedence <= PREC_ASSIGNMENT;
 
         prefix(self, can_assign)?;
 
         while precedence <= Self::get_rule(self.current.ty).precedence {
-            self.advance()?;
+            self.advance();
             let ParseRule { infix, .. } = Self::get_rule(self.previous.ty);
             let infix = infix.unwrap();
             infix(self, can_assign)?
@@ -277,16 +1069,17 @@ impl<'a, 'b: 'a> Parser<'a, 'b> {
             .parse()
             .expect("Not a number, scanner bugged out");
 
-        self.emit_constant(Value::from(val));
-        Ok(())
+        self.emit_constant(Value::from(val))
     }
 
     fn string(&mut self, _can_assign: bool) -> Result<()> {
         let length = self.previous.raw.len();
         // remove open close quotes
-        let value = Value::from(self.previous.raw.get(1..length - 1).unwrap());
-        self.emit_constant(value);
-        Ok(())
+        let s = self
+            .heap
+            .intern(self.previous.raw.get(1..length - 1).unwrap());
+        let value = Value::Object(Obj::String(s));
+        self.emit_constant(value)
     }
 
     fn variable(&mut self, can_assign: bool) -> Result<()> {
@@ -295,30 +1088,117 @@ impl<'a, 'b: 'a> Parser<'a, 'b> {
     }
 
     fn named_variable(&mut self, name: Token<'a>, can_assign: bool) -> Result<()> {
-        let identifier: ValueOwned = Value::from(name.raw);
-        let offset = self.chunk.add_constant(identifier);
+        let local = self
+            .compiler
+            .resolve_local(&name)
+            .map_err(|message| self.error(message))?;
+        let (get_op, set_op, offset) = match local {
+            Some(slot) => ((OP_GET_LOCAL, None), (OP_SET_LOCAL, None), slot),
+            None => {
+                let upvalue = self
+                    .compiler
+                    .resolve_upvalue(&name)
+                    .map_err(|message| self.error(message))?;
+                match upvalue {
+                    Some(index) => ((OP_GET_UPVALUE, None), (OP_SET_UPVALUE, None), index),
+                    None => {
+                        let offset = self.identifier_constant(&name)?;
+                        (
+                            (OP_GET_GLOBAL, Some(OP_GET_GLOBAL_LONG)),
+                            (OP_SET_GLOBAL, Some(OP_SET_GLOBAL_LONG)),
+                            offset,
+                        )
+                    }
+                }
+            }
+        };
 
         if can_assign && self.match_ty(TokenType::TOKEN_EQUAL) {
             self.expression()?;
-            self.emit_bytes(OP_SET_GLOBAL, offset as u8);
+            self.emit_operand(set_op, offset)?;
         } else {
-            self.emit_bytes(OP_GET_GLOBAL, offset as u8);
+            self.emit_operand(get_op, offset)?;
         }
 
         Ok(())
     }
 
     fn define_variable(&mut self, global: usize) -> Result<()> {
-        self.emit_bytes(OP_DEFINE_GLOBAL, global as u8);
-        Ok(())
+        if self.compiler.scope_depth > 0 {
+            self.mark_initialized();
+            return Ok(());
+        }
+
+        self.emit_operand((OP_DEFINE_GLOBAL, Some(OP_DEFINE_GLOBAL_LONG)), global)
     }
 
-    // result constant for identifier name offset
+    fn mark_initialized(&mut self) {
+        let scope_depth = self.compiler.scope_depth;
+        if scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = self.compiler.locals.last_mut() {
+            local.depth = Some(scope_depth);
+        }
+    }
+
+    // result constant for identifier name offset, unused for locals
     fn parse_variable(&mut self, message: &str) -> Result<usize> {
         self.consume_with_error_message(TokenType::TOKEN_IDENTIFIER, message)?;
-        let identifier: ValueOwned = Value::from(self.previous.raw);
-        let offset = self.chunk.add_constant(identifier);
-        Ok(offset)
+
+        if self.compiler.scope_depth > 0 {
+            self.declare_variable()?;
+            return Ok(0);
+        }
+
+        let previous = self.previous;
+        self.identifier_constant(&previous)
+    }
+
+    fn identifier_constant(&mut self, name: &Token<'a>) -> Result<usize> {
+        let identifier: ValueOwned = Value::Object(Obj::String(self.heap.intern(name.raw)));
+        self.make_constant(identifier)
+    }
+
+    fn make_constant(&mut self, value: ValueOwned) -> Result<usize> {
+        let index = self.current_chunk().add_constant(value);
+        if index >= MAX_CONSTANTS {
+            return Err(self.too_many_constants());
+        }
+
+        Ok(index)
+    }
+
+    fn declare_variable(&mut self) -> Result<()> {
+        let name = self.previous;
+        let scope_depth = self.compiler.scope_depth;
+
+        let redeclared = self
+            .compiler
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name.identifier_equal(&name));
+
+        if redeclared {
+            return Err(self.error("Variable with this name already declared in this scope"));
+        }
+
+        self.add_local(name)
+    }
+
+    fn add_local(&mut self, name: Token<'a>) -> Result<()> {
+        if self.compiler.locals.len() >= MAX_LOCALS {
+            return Err(self.error("Too many local variables in function"));
+        }
+
+        self.compiler.locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+        Ok(())
     }
 
     fn check_ty(&self, ty: TokenType) -> bool {
@@ -328,14 +1208,11 @@ impl<'a, 'b: 'a> Parser<'a, 'b> {
         if !self.check_ty(ty) {
             false
         } else {
-            self.advance().is_ok()
+            self.advance();
+            true
         }
     }
 
-    #[inline]
-    fn consume(&mut self, ty: TokenType) -> Result<()> {
-        self._consume(ty, None)
-    }
     #[inline]
     fn consume_with_error_message(&mut self, ty: TokenType, message: &str) -> Result<()> {
         self._consume(ty, Some(message))
@@ -343,12 +1220,20 @@ impl<'a, 'b: 'a> Parser<'a, 'b> {
 
     fn _consume(&mut self, ty: TokenType, message: Option<&str>) -> Result<()> {
         if self.current.ty == ty {
-            self.advance()
+            self.advance();
+            Ok(())
         } else {
-            Err(CompileError {
-                line_no: self.current.line,
-                payload: CompileErrorPayload::UnexpectedToken(self.current.ty),
-                message: message.map(|s| s.to_string()),
+            let error = CompileError::at(
+                &self.current,
+                CompileErrorPayload::UnexpectedToken(self.current.ty),
+                message.map(|s| s.to_string()),
+            );
+            Err(match (self.current.ty, ty) {
+                (TokenType::TOKEN_EOF, _) => {
+                    error.with_help("the source ended before this was complete")
+                }
+                (_, TokenType::TOKEN_SEMICOLON) => error.with_help("add a ; to end the statement"),
+                _ => error,
             })
         }
     }
@@ -362,47 +1247,122 @@ impl<'a, 'b: 'a> Parser<'a, 'b> {
         Self::error_at(&self.current, message)
     }
 
+    fn too_many_constants(&self) -> CompileError {
+        CompileError::at(
+            &self.previous,
+            CompileErrorPayload::TooManyConstants,
+            Some("Too many constants in one chunk".to_string()),
+        )
+        .with_help("split the code into smaller functions")
+    }
+
     fn error_at(token: &Token<'a>, message: &str) -> CompileError {
-        CompileError {
-            line_no: token.line,
-            payload: CompileErrorPayload::ParserError,
-            message: Some(message.to_string()),
-        }
+        CompileError::at(
+            token,
+            CompileErrorPayload::ParserError,
+            Some(message.to_string()),
+        )
     }
 
-    fn advance(&mut self) -> Result<()> {
+    // moves to the next valid token, error tokens are reported and skipped
+    fn advance(&mut self) {
         mem::swap(&mut self.previous, &mut self.current);
 
-        self.current = self.scanner.scan_token();
+        loop {
+            self.current = self.scanner.scan_token();
+            if self.current.ty != TokenType::TOKEN_ERROR {
+                break;
+            }
 
-        if self.current.ty == TokenType::TOKEN_ERROR {
-            Err(CompileError {
-                line_no: self.current.line,
-                payload: CompileErrorPayload::ScannerError,
-                message: Some(self.current.raw.to_string()),
-            })
-        } else {
-            Ok(())
+            let mut error = CompileError::at(
+                &self.current,
+                CompileErrorPayload::ScannerError,
+                Some(self.current.raw.to_string()),
+            );
+            error.help = self.current.help;
+            self.report(error);
         }
     }
 
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.compiler.function.chunk
+    }
+
     fn emit_byte(&mut self, b: u8) {
-        self.chunk.write(b, self.previous.line);
+        let line = self.previous.line;
+        self.current_chunk().write(b, line);
     }
     fn emit_bytes(&mut self, b1: u8, b2: u8) {
         self.emit_byte(b1);
         self.emit_byte(b2);
     }
-    fn emit_constant(&mut self, c: ValueOwned) {
-        // Check constants array limit
-        self.chunk.write_constant(c, self.previous.line);
+    // emits a jump with a placeholder offset, returns the offset to patch
+    fn emit_jump(&mut self, instruction: u8) -> usize {
+        self.emit_byte(instruction);
+        self.emit_bytes(0xff, 0xff);
+        self.current_chunk().code_len() - 2
+    }
+    fn emit_loop(&mut self, loop_start: usize) -> Result<()> {
+        self.emit_byte(OP_LOOP);
+
+        // +2 to jump over the loop offset itself
+        let offset = self.current_chunk().code_len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            return Err(self.error("Loop body too large"));
+        }
+
+        self.emit_bytes((offset >> 8) as u8, offset as u8);
+        Ok(())
+    }
+    fn patch_jump(&mut self, offset: usize) -> Result<()> {
+        // -2 to adjust for the jump offset itself
+        let jump = self.current_chunk().code_len() - offset - 2;
+
+        if jump > u16::MAX as usize {
+            return Err(self.error("Too much code to jump over"));
+        }
+
+        self.current_chunk().patch_short(offset, jump as u16);
+        Ok(())
+    }
+    fn emit_return(&mut self) {
+        // initializers always return the instance
+        if self.compiler.function_type == FunctionType::Initializer {
+            self.emit_bytes(OP_GET_LOCAL, 0);
+        } else {
+            self.emit_byte(OP_NIL);
+        }
+
+        self.emit_byte(OP_RETURN);
+    }
+    fn emit_constant(&mut self, c: ValueOwned) -> Result<()> {
+        let index = self.make_constant(c)?;
+        self.emit_operand((OP_CONSTANT, Some(OP_CONSTANT_LONG)), index)
+    }
+    // emits the short form of an instruction when the operand fits in a
+    // byte, the long form otherwise
+    fn emit_operand(&mut self, (op, long_op): (u8, Option<u8>), operand: usize) -> Result<()> {
+        if operand <= u8::MAX as usize {
+            self.emit_bytes(op, operand as u8);
+            return Ok(());
+        }
+
+        match long_op {
+            Some(long_op) => {
+                self.emit_byte(long_op);
+                let line = self.previous.line;
+                self.current_chunk().write_long(operand, line);
+                Ok(())
+            }
+            None => Err(self.too_many_constants()),
+        }
     }
 
-    fn get_rule(ty: TokenType) -> ParseRule<'a, 'b> {
+    fn get_rule(ty: TokenType) -> ParseRule<'a> {
         match ty {
             TokenType::TOKEN_LEFT_PAREN => ParseRule {
                 prefix: Some(Parser::grouping),
-                infix: None,
+                infix: Some(Parser::call),
                 precedence: PREC_CALL,
             },
             TokenType::TOKEN_RIGHT_PAREN => ParseRule {
@@ -427,7 +1387,7 @@ impl<'a, 'b: 'a> Parser<'a, 'b> {
             },
             TokenType::TOKEN_DOT => ParseRule {
                 prefix: None,
-                infix: None,
+                infix: Some(Parser::dot),
                 precedence: PREC_CALL,
             },
             TokenType::TOKEN_MINUS => ParseRule {
@@ -512,7 +1472,7 @@ impl<'a, 'b: 'a> Parser<'a, 'b> {
             },
             TokenType::TOKEN_AND => ParseRule {
                 prefix: None,
-                infix: None,
+                infix: Some(Parser::and),
                 precedence: PREC_AND,
             },
             TokenType::TOKEN_CLASS => ParseRule {
@@ -552,7 +1512,7 @@ impl<'a, 'b: 'a> Parser<'a, 'b> {
             },
             TokenType::TOKEN_OR => ParseRule {
                 prefix: None,
-                infix: None,
+                infix: Some(Parser::or),
                 precedence: PREC_OR,
             },
             TokenType::TOKEN_PRINT => ParseRule {
@@ -566,12 +1526,12 @@ impl<'a, 'b: 'a> Parser<'a, 'b> {
                 precedence: PREC_NONE,
             },
             TokenType::TOKEN_SUPER => ParseRule {
-                prefix: None,
+                prefix: Some(Parser::super_),
                 infix: None,
                 precedence: PREC_NONE,
             },
             TokenType::TOKEN_THIS => ParseRule {
-                prefix: None,
+                prefix: Some(Parser::this),
                 infix: None,
                 precedence: PREC_NONE,
             },
@@ -603,3 +1563,195 @@ impl<'a, 'b: 'a> Parser<'a, 'b> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile_errors(source: &str) -> Vec<CompileError> {
+        let mut heap = Heap::new();
+        compile(source, &mut heap).err().unwrap_or_default()
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let errors = compile_errors(
+            "
+            var = 1;
+            print 1 +;
+            if (true print 3;
+            class A < A {}
+            print @;
+            var ok = 3;
+            ",
+        );
+        let lines: Vec<u64> = errors.iter().map(|e| e.line_no).collect();
+
+        assert_eq!(lines, vec![2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn recovers_inside_nested_functions() {
+        let errors = compile_errors(
+            "
+            fun outer() {
+                fun inner() {
+                    return 1 +;
+                }
+                return inner;
+            }
+            print outer()();
+            ",
+        );
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line_no, 4);
+    }
+
+    #[test]
+    fn unwinds_functions_and_classes_left_open() {
+        let errors = compile_errors(
+            "
+            class A < B { m(a b) { this.x = 1; } }
+            fun f(a) { for (var i = 0; i < ; i = i + 1) {} }
+            print this;
+            ",
+        );
+
+        // this outside of a class is still detected after the class
+        // compiler was unwound
+        assert!(errors.iter().any(|e| e.line_no == 4
+            && e.message.as_deref() == Some("Cannot use this outside of a class")));
+    }
+
+    fn messages(source: &str) -> Vec<String> {
+        compile_errors(source)
+            .into_iter()
+            .filter_map(|e| e.message)
+            .collect()
+    }
+
+    #[test]
+    fn locals_are_checked_at_compile_time() {
+        assert_eq!(
+            messages("{ var a = a; }"),
+            ["Cannot read local variable in its own initializer"]
+        );
+        assert_eq!(
+            messages("{ var a = 1; var a = 2; }"),
+            ["Variable with this name already declared in this scope"]
+        );
+        // globals and locals of enclosing blocks can be shadowed
+        assert!(messages("var a = 1; var a = 2; { var a = 3; { var a = 4; } }").is_empty());
+    }
+
+    #[test]
+    fn loop_bodies_are_limited_to_the_jump_distance() {
+        // every print takes 3 bytes of code
+        let body = "print 1;\n".repeat(22_000);
+        let source = format!("while (true) {{\n{}}}\n", body);
+
+        assert!(messages(&source).contains(&"Loop body too large".to_string()));
+    }
+
+    #[test]
+    fn return_needs_a_function() {
+        assert_eq!(messages("return 1;"), ["Cannot return from top-level code"]);
+        assert!(messages("fun f() { return; }").is_empty());
+    }
+
+    #[test]
+    fn initializers_cannot_return_values() {
+        assert_eq!(
+            messages("class A { init() { return 1; } }"),
+            ["Cannot return a value from an initializer"]
+        );
+        assert_eq!(
+            messages("fun f() { return this; }"),
+            ["Cannot use this outside of a class"]
+        );
+    }
+
+    #[test]
+    fn super_needs_a_superclass() {
+        assert_eq!(
+            messages("class A < A {}"),
+            ["A class cannot inherit from itself"]
+        );
+        assert_eq!(
+            messages("class A { m() { return super.m(); } }"),
+            ["Cannot use super in a class with no superclass"]
+        );
+        assert_eq!(
+            messages("fun f() { super.m(); }"),
+            ["Cannot use super outside of a class"]
+        );
+    }
+
+    #[test]
+    fn errors_have_columns_and_spans() {
+        let source = "var a = 1;\nprint a +\t@ 2;\nprint \"unterminated";
+        let errors = compile_errors(source);
+
+        let positions: Vec<(u64, u64, &str)> = errors
+            .iter()
+            .map(|e| (e.line_no, e.column, &source[e.span.start..e.span.end]))
+            .collect();
+        assert_eq!(positions, vec![(2, 11, "@"), (3, 7, "\"unterminated")]);
+    }
+
+    #[test]
+    fn constant_pools_are_limited() {
+        let source =
+            |count: usize| -> String { (0..count).map(|i| format!("print {};", i)).collect() };
+        assert!(compile_errors(&source(MAX_CONSTANTS)).is_empty());
+
+        let errors = compile_errors(&source(MAX_CONSTANTS + 1));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code(), "E004");
+        assert_eq!(errors[0].message(), "Too many constants in one chunk");
+    }
+
+    #[test]
+    fn errors_carry_the_help_of_where_they_were_raised() {
+        let helps = |source| -> Vec<Option<&'static str>> {
+            compile_errors(source).iter().map(|e| e.help()).collect()
+        };
+
+        assert_eq!(
+            helps("print 1"),
+            [Some("the source ended before this was complete")]
+        );
+        assert_eq!(
+            helps("print 1 print 2;"),
+            [Some("add a ; to end the statement")]
+        );
+        assert_eq!(
+            helps("print ;"),
+            [Some("a value, variable or call was expected here")]
+        );
+        assert_eq!(helps("@"), [Some("remove this character")]);
+        assert_eq!(helps("\"a"), [Some("add a closing \" to end the string")]);
+        assert_eq!(helps("fun f( {}"), [None]);
+    }
+
+    #[test]
+    fn render_underlines_the_span() {
+        let source = "print 1;\n  print foo bar;\n";
+        let errors = compile_errors(source);
+
+        assert_eq!(
+            errors[0].render(source, false),
+            "error[E003]: Expect ; after print statement\n \
+             --> line 2, column 13\n  \
+             |\n\
+             2 |   print foo bar;\n  \
+             |             ^^^\n  \
+             = help: add a ; to end the statement\n"
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "[line 2:13] error[E003]: Expect ; after print statement"
+        );
+    }
+}
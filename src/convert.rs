@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+
+use gc::{Gc, Heap};
+use object::{Obj, ObjInstance, ObjList};
+use value::{Value, ValueOwned};
+
+// vectors are converted to lists, which scripts use through the list
+// natives. Lox has no map type, maps are converted to instances of a
+// builtin class with the entries as fields.
+const MAP_CLASS: &str = "Map";
+
+// host values that can be turned into Lox values, objects are allocated in
+// the given heap
+pub trait ToLox {
+    fn to_lox(self, heap: &mut Heap) -> ValueOwned;
+}
+
+// host values that can be read from Lox values whose objects live in heap,
+// borrowed results such as &str borrow from the heap
+pub trait FromLox<'h>: Sized {
+    fn from_lox(value: &ValueOwned, heap: &'h Heap) -> Result<Self, ConversionError>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionError {
+    pub expected: String,
+    pub found: String,
+    // location of the value inside the converted one, like [2].name, empty
+    // when the converted value itself did not match
+    pub path: String,
+}
+
+impl ConversionError {
+    fn new<S: Into<String>>(expected: S, value: &ValueOwned) -> Self {
+        ConversionError {
+            expected: expected.into(),
+            found: describe(value),
+            path: String::new(),
+        }
+    }
+    // prefixes the path with the location of the value in its parent
+    fn within(mut self, location: String) -> Self {
+        self.path.insert_str(0, &location);
+        self
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected {}, found {}", self.expected, self.found)?;
+        if !self.path.is_empty() {
+            write!(f, " at {}", self.path)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl error::Error for ConversionError {}
+
+fn describe(value: &ValueOwned) -> String {
+    match value {
+        Value::Nil => "nil".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Object(Obj::Instance(_)) => "an instance".to_string(),
+        Value::Object(_) => format!("a {}", value.type_name()),
+    }
+}
+
+impl ToLox for ValueOwned {
+    fn to_lox(self, _heap: &mut Heap) -> ValueOwned {
+        self
+    }
+}
+impl<'h> FromLox<'h> for ValueOwned {
+    fn from_lox(value: &ValueOwned, _heap: &'h Heap) -> Result<Self, ConversionError> {
+        Ok(value.clone())
+    }
+}
+
+impl ToLox for () {
+    fn to_lox(self, _heap: &mut Heap) -> ValueOwned {
+        Value::Nil
+    }
+}
+
+impl ToLox for bool {
+    fn to_lox(self, _heap: &mut Heap) -> ValueOwned {
+        Value::Bool(self)
+    }
+}
+impl<'h> FromLox<'h> for bool {
+    fn from_lox(value: &ValueOwned, _heap: &'h Heap) -> Result<Self, ConversionError> {
+        match *value {
+            Value::Bool(b) => Ok(b),
+            _ => Err(ConversionError::new("a bool", value)),
+        }
+    }
+}
+
+impl ToLox for f64 {
+    fn to_lox(self, _heap: &mut Heap) -> ValueOwned {
+        Value::Number(self)
+    }
+}
+impl<'h> FromLox<'h> for f64 {
+    fn from_lox(value: &ValueOwned, _heap: &'h Heap) -> Result<Self, ConversionError> {
+        match *value {
+            Value::Number(n) => Ok(n),
+            _ => Err(ConversionError::new("a number", value)),
+        }
+    }
+}
+
+// integers convert to the nearest number, which loses precision for 64 bit
+// integers past 2^53. Numbers convert to integers when they are whole and in
+// range. MAX + 1 is a power of two and exact as a float, unlike the MAX of
+// 64 bit integers which rounds up to it.
+macro_rules! integer_conversions {
+    ($($ty:ident)*) => {
+        $(
+            impl ToLox for $ty {
+                fn to_lox(self, _heap: &mut Heap) -> ValueOwned {
+                    Value::Number(self as f64)
+                }
+            }
+            impl<'h> FromLox<'h> for $ty {
+                fn from_lox(value: &ValueOwned, _heap: &'h Heap) -> Result<Self, ConversionError> {
+                    match *value {
+                        Value::Number(n)
+                            if n.fract() == 0.0
+                                && n >= $ty::MIN as f64
+                                && n < $ty::MAX as f64 + 1.0 =>
+                        {
+                            Ok(n as $ty)
+                        }
+                        _ => Err(ConversionError::new(
+                            format!("an integer between {} and {}", $ty::MIN, $ty::MAX),
+                            value,
+                        )),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+integer_conversions!(i8 i16 i32 i64 isize u8 u16 u32 u64 usize);
+
+impl ToLox for String {
+    fn to_lox(self, heap: &mut Heap) -> ValueOwned {
+        self.as_str().to_lox(heap)
+    }
+}
+impl ToLox for &str {
+    fn to_lox(self, heap: &mut Heap) -> ValueOwned {
+        Value::Object(Obj::String(heap.intern(self)))
+    }
+}
+impl<'h> FromLox<'h> for String {
+    fn from_lox(value: &ValueOwned, heap: &'h Heap) -> Result<Self, ConversionError> {
+        <&str>::from_lox(value, heap).map(str::to_owned)
+    }
+}
+impl<'h> FromLox<'h> for &'h str {
+    fn from_lox(value: &ValueOwned, heap: &'h Heap) -> Result<Self, ConversionError> {
+        match *value {
+            Value::Object(Obj::String(s)) => Ok(&heap.get(s)[..]),
+            _ => Err(ConversionError::new("a string", value)),
+        }
+    }
+}
+
+// None is nil
+impl<T: ToLox> ToLox for Option<T> {
+    fn to_lox(self, heap: &mut Heap) -> ValueOwned {
+        match self {
+            Some(value) => value.to_lox(heap),
+            None => Value::Nil,
+        }
+    }
+}
+impl<'h, T: FromLox<'h>> FromLox<'h> for Option<T> {
+    fn from_lox(value: &ValueOwned, heap: &'h Heap) -> Result<Self, ConversionError> {
+        match *value {
+            Value::Nil => Ok(None),
+            _ => T::from_lox(value, heap).map(Some),
+        }
+    }
+}
+
+impl<T: ToLox> ToLox for Vec<T> {
+    fn to_lox(self, heap: &mut Heap) -> ValueOwned {
+        let elements = self
+            .into_iter()
+            .map(|element| element.to_lox(heap))
+            .collect();
+
+        new_list(heap, elements)
+    }
+}
+impl<'h, T: FromLox<'h>> FromLox<'h> for Vec<T> {
+    fn from_lox(value: &ValueOwned, heap: &'h Heap) -> Result<Self, ConversionError> {
+        let list = as_list(value).ok_or_else(|| ConversionError::new("a list", value))?;
+
+        heap.get(list)
+            .elements
+            .iter()
+            .enumerate()
+            .map(|(i, element)| {
+                T::from_lox(element, heap).map_err(|e| e.within(format!("[{}]", i)))
+            })
+            .collect()
+    }
+}
+
+impl<T: ToLox> ToLox for HashMap<String, T> {
+    fn to_lox(self, heap: &mut Heap) -> ValueOwned {
+        let fields: Vec<(String, ValueOwned)> = self
+            .into_iter()
+            .map(|(name, value)| (name, value.to_lox(heap)))
+            .collect();
+
+        new_instance(heap, MAP_CLASS, fields)
+    }
+}
+impl<'h, T: FromLox<'h>> FromLox<'h> for HashMap<String, T> {
+    fn from_lox(value: &ValueOwned, heap: &'h Heap) -> Result<Self, ConversionError> {
+        // instances of other classes are objects of the script, not maps
+        let instance = match *value {
+            Value::Object(Obj::Instance(instance))
+                if heap.find_builtin_class(MAP_CLASS) == Some(heap.get(instance).class) =>
+            {
+                heap.get(instance)
+            }
+            _ => return Err(ConversionError::new("a map", value)),
+        };
+
+        instance
+            .fields
+            .iter()
+            .map(|(&name, value)| {
+                let name = heap.get(name)[..].to_owned();
+                match T::from_lox(value, heap) {
+                    Ok(value) => Ok((name, value)),
+                    Err(e) => Err(e.within(format!(".{}", name))),
+                }
+            })
+            .collect()
+    }
+}
+
+fn new_instance(
+    heap: &mut Heap,
+    class: &'static str,
+    fields: Vec<(String, ValueOwned)>,
+) -> ValueOwned {
+    let class = heap.builtin_class(class);
+    let mut instance = ObjInstance::new(class);
+    for (name, value) in fields {
+        instance.fields.insert(heap.intern(&name), value);
+    }
+
+    Value::Object(Obj::Instance(heap.alloc(instance)))
+}
+
+pub fn new_list(heap: &mut Heap, elements: Vec<ValueOwned>) -> ValueOwned {
+    Value::Object(Obj::List(heap.alloc(ObjList::new(elements))))
+}
+
+pub fn as_list(value: &ValueOwned) -> Option<Gc<ObjList>> {
+    match *value {
+        Value::Object(Obj::List(list)) => Some(list),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object::{ObjClass, ObjString};
+
+    fn round_trip<T>(value: T) -> T
+    where
+        T: ToLox + for<'h> FromLox<'h>,
+    {
+        let mut heap = Heap::new();
+        let value = value.to_lox(&mut heap);
+        T::from_lox(&value, &heap).unwrap()
+    }
+
+    #[test]
+    fn converts_scalars() {
+        assert_eq!(round_trip(1.5), 1.5);
+        assert_eq!(round_trip(-3i32), -3);
+        assert_eq!(round_trip(255u8), 255);
+        assert!(round_trip(true));
+        assert_eq!(round_trip("lox".to_string()), "lox");
+        assert_eq!(round_trip(Some(2u64)), Some(2));
+        assert_eq!(round_trip(None::<f64>), None);
+
+        let mut heap = Heap::new();
+        let value = "borrowed".to_lox(&mut heap);
+        assert_eq!(<&str>::from_lox(&value, &heap), Ok("borrowed"));
+    }
+
+    #[test]
+    fn converts_collections() {
+        let list = vec![
+            vec!["a".to_string()],
+            vec![],
+            vec!["b".to_string(), "c".to_string()],
+        ];
+        assert_eq!(round_trip(list.clone()), list);
+
+        let mut map = HashMap::new();
+        map.insert("x".to_string(), vec![1.0, 2.0]);
+        map.insert("y".to_string(), vec![]);
+        assert_eq!(round_trip(map.clone()), map);
+    }
+
+    #[test]
+    fn instances_are_not_lists() {
+        let mut heap = Heap::new();
+        let class = heap.alloc(ObjClass::new(ObjString::new("List")));
+        let mut instance = ObjInstance::new(class);
+        instance
+            .fields
+            .insert(heap.intern("length"), Value::Number(0.0));
+        let instance = Value::Object(Obj::Instance(heap.alloc(instance)));
+
+        assert_eq!(
+            Vec::<f64>::from_lox(&instance, &heap)
+                .unwrap_err()
+                .to_string(),
+            "expected a list, found an instance"
+        );
+    }
+
+    #[test]
+    fn errors_describe_the_mismatch() {
+        let mut heap = Heap::new();
+        let error = |value: &ValueOwned, heap: &Heap| u8::from_lox(value, heap).unwrap_err();
+
+        assert_eq!(
+            error(&Value::Number(256.0), &heap).to_string(),
+            "expected an integer between 0 and 255, found 256"
+        );
+        assert_eq!(
+            error(&Value::Number(1.5), &heap).to_string(),
+            "expected an integer between 0 and 255, found 1.5"
+        );
+        let s = "1".to_lox(&mut heap);
+        assert_eq!(
+            error(&s, &heap).to_string(),
+            "expected an integer between 0 and 255, found a string"
+        );
+
+        let mut map = HashMap::new();
+        map.insert("items".to_string(), vec![Some(1.0), None]);
+        let value = map.to_lox(&mut heap);
+        let e = HashMap::<String, Vec<f64>>::from_lox(&value, &heap).unwrap_err();
+        assert_eq!(e.to_string(), "expected a number, found nil at .items[1]");
+
+        let e = Vec::<f64>::from_lox(&Value::Nil, &heap).unwrap_err();
+        assert_eq!(e.to_string(), "expected a list, found nil");
+    }
+
+    #[test]
+    fn maps_are_only_read_from_the_map_class() {
+        let mut heap = Heap::new();
+        let error = |value: &ValueOwned, heap: &Heap| {
+            HashMap::<String, f64>::from_lox(value, heap)
+                .unwrap_err()
+                .to_string()
+        };
+
+        let list = vec![1.0].to_lox(&mut heap);
+        assert_eq!(error(&list, &heap), "expected a map, found a list");
+
+        let class = heap.alloc(ObjClass::new(ObjString::new("Map")));
+        let instance = Value::Object(Obj::Instance(heap.alloc(ObjInstance::new(class))));
+        assert_eq!(error(&instance, &heap), "expected a map, found an instance");
+    }
+
+    #[test]
+    fn integers_reject_values_past_their_range() {
+        let heap = Heap::new();
+        let number = |n: f64| Value::Number(n);
+        let two_to_the = |bits: i32| 2f64.powi(bits);
+
+        assert!(u64::from_lox(&number(two_to_the(64)), &heap).is_err());
+        assert_eq!(u64::from_lox(&number(two_to_the(63)), &heap), Ok(1 << 63));
+        assert!(i64::from_lox(&number(two_to_the(63)), &heap).is_err());
+        assert_eq!(i64::from_lox(&number(-two_to_the(63)), &heap), Ok(i64::MIN));
+        assert!(i64::from_lox(&number(-two_to_the(63) - 2048.0), &heap).is_err());
+        assert!(usize::from_lox(&number(-1.0), &heap).is_err());
+        assert_eq!(i8::from_lox(&number(-128.0), &heap), Ok(-128));
+        assert!(i8::from_lox(&number(128.0), &heap).is_err());
+    }
+
+    #[test]
+    fn large_integers_round_to_the_nearest_number() {
+        let mut heap = Heap::new();
+
+        assert_eq!(round_trip(1u64 << 53), 1 << 53);
+        assert_eq!(round_trip((1u64 << 53) + 1), 1 << 53);
+        assert_eq!(round_trip(i64::MIN), i64::MIN);
+        // MAX rounds up to 2^64, past the range it came from
+        let max = u64::MAX.to_lox(&mut heap);
+        assert_eq!(max, Value::Number(2f64.powi(64)));
+        assert!(u64::from_lox(&max, &heap).is_err());
+    }
+}
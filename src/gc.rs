@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use object::{
+    hash_str, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjList,
+    ObjNative, ObjString, ObjUpvalue,
+};
+use value::{Value, ValueOwned};
+
+// collection threshold before the first collection
+const INITIAL_NEXT_GC: usize = 1024 * 1024;
+const HEAP_GROW_FACTOR: usize = 2;
+
+// identities of the heaps created so far
+static HEAP_IDS: AtomicUsize = AtomicUsize::new(0);
+
+// handle to an object of type T living in a Heap
+pub struct Gc<T> {
+    index: usize,
+    marker: PhantomData<T>,
+}
+
+impl<T> Gc<T> {
+    fn new(index: usize) -> Self {
+        Gc {
+            index,
+            marker: PhantomData,
+        }
+    }
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+// implemented by hand, derives would require T to implement these too
+impl<T> Copy for Gc<T> {}
+impl<T> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> PartialEq for Gc<T> {
+    fn eq(&self, other: &Gc<T>) -> bool {
+        self.index == other.index
+    }
+}
+impl<T> Eq for Gc<T> {}
+impl<T> Hash for Gc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+    }
+}
+impl<T> fmt::Debug for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Gc({})", self.index)
+    }
+}
+
+pub enum HeapObj {
+    BoundMethod(ObjBoundMethod),
+    Class(ObjClass),
+    Closure(ObjClosure),
+    Function(ObjFunction),
+    Instance(ObjInstance),
+    List(ObjList),
+    Native(ObjNative),
+    String(ObjString),
+    Upvalue(ObjUpvalue),
+}
+
+impl HeapObj {
+    // rough number of bytes owned by the object, drives collection pacing
+    fn size(&self) -> usize {
+        let owned = match self {
+            HeapObj::Class(ref class) => {
+                class.methods.len() * mem::size_of::<(Gc<ObjString>, Gc<ObjClosure>)>()
+            }
+            HeapObj::Closure(ref closure) => {
+                closure.upvalues.len() * mem::size_of::<Gc<ObjUpvalue>>()
+            }
+            HeapObj::Function(ref function) => function.chunk.size(),
+            HeapObj::Instance(ref instance) => {
+                instance.fields.len() * mem::size_of::<(Gc<ObjString>, ValueOwned)>()
+            }
+            HeapObj::List(ref list) => list.elements.len() * mem::size_of::<ValueOwned>(),
+            HeapObj::String(ref s) => s.len(),
+            HeapObj::BoundMethod(_) | HeapObj::Native(_) | HeapObj::Upvalue(_) => 0,
+        };
+
+        mem::size_of::<Slot>() + owned
+    }
+
+    // pushes the heap index of every object referenced by this one
+    fn trace(&self, gray: &mut Vec<usize>) {
+        match self {
+            HeapObj::BoundMethod(ref bound) => {
+                trace_value(&bound.receiver, gray);
+                gray.push(bound.method.index());
+            }
+            HeapObj::Class(ref class) => {
+                for (name, method) in class.methods.iter() {
+                    gray.push(name.index());
+                    gray.push(method.index());
+                }
+            }
+            HeapObj::Closure(ref closure) => {
+                gray.push(closure.function.index());
+                gray.extend(closure.upvalues.iter().map(|upvalue| upvalue.index()));
+            }
+            HeapObj::Function(ref function) => {
+                for constant in function.chunk.constants() {
+                    trace_value(constant, gray);
+                }
+            }
+            HeapObj::Instance(ref instance) => {
+                gray.push(instance.class.index());
+                for (name, value) in instance.fields.iter() {
+                    gray.push(name.index());
+                    trace_value(value, gray);
+                }
+            }
+            HeapObj::List(ref list) => {
+                for element in list.elements.iter() {
+                    trace_value(element, gray);
+                }
+            }
+            HeapObj::Upvalue(ObjUpvalue::Closed(ref value)) => trace_value(value, gray),
+            HeapObj::Native(_) | HeapObj::String(_) | HeapObj::Upvalue(ObjUpvalue::Open(_)) => {}
+        }
+    }
+}
+
+fn trace_value(value: &ValueOwned, gray: &mut Vec<usize>) {
+    if let Value::Object(ref obj) = value {
+        gray.push(obj.index());
+    }
+}
+
+// object types that can be stored in a Heap
+pub trait HeapAllocated: Sized {
+    fn into_heap_obj(self) -> HeapObj;
+    fn from_heap_obj(obj: &HeapObj) -> Option<&Self>;
+    fn from_heap_obj_mut(obj: &mut HeapObj) -> Option<&mut Self>;
+}
+
+macro_rules! heap_allocated {
+    ($ty:ident, $variant:ident) => {
+        impl HeapAllocated for $ty {
+            fn into_heap_obj(self) -> HeapObj {
+                HeapObj::$variant(self)
+            }
+            fn from_heap_obj(obj: &HeapObj) -> Option<&Self> {
+                match obj {
+                    HeapObj::$variant(ref o) => Some(o),
+                    _ => None,
+                }
+            }
+            fn from_heap_obj_mut(obj: &mut HeapObj) -> Option<&mut Self> {
+                match obj {
+                    HeapObj::$variant(ref mut o) => Some(o),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+heap_allocated!(ObjBoundMethod, BoundMethod);
+heap_allocated!(ObjClass, Class);
+heap_allocated!(ObjClosure, Closure);
+heap_allocated!(ObjFunction, Function);
+heap_allocated!(ObjInstance, Instance);
+heap_allocated!(ObjList, List);
+heap_allocated!(ObjNative, Native);
+heap_allocated!(ObjString, String);
+heap_allocated!(ObjUpvalue, Upvalue);
+
+struct Slot {
+    marked: bool,
+    // size when last accounted, see resized
+    size: usize,
+    obj: HeapObj,
+}
+
+// owns every object created by the compiler and the vm, unreachable
+// objects are reclaimed by a mark and sweep collection. The heap never
+// collects on its own, the owner marks its roots and calls collect at a
+// point where every live object is reachable from them.
+pub struct Heap {
+    // tells handles of this heap apart from those of another one
+    id: usize,
+    slots: Vec<Option<Slot>>,
+    free: Vec<usize>,
+    gray: Vec<usize>,
+    // every live string by content hash, equal strings share one object
+    strings: HashMap<u64, Vec<Gc<ObjString>>>,
+    // classes shared by the values converted from host maps, they are
+    // created on first use and never collected
+    builtin_classes: HashMap<&'static str, Gc<ObjClass>>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    allocated_since_gc: bool,
+    // collect whenever anything was allocated, to shake out rooting bugs
+    stress: bool,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            id: HEAP_IDS.fetch_add(1, Ordering::Relaxed),
+            slots: Vec::new(),
+            free: Vec::new(),
+            gray: Vec::new(),
+            strings: HashMap::new(),
+            builtin_classes: HashMap::new(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_NEXT_GC,
+            allocated_since_gc: false,
+            stress: false,
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    pub fn alloc<T: HeapAllocated>(&mut self, obj: T) -> Gc<T> {
+        let obj = obj.into_heap_obj();
+        let size = obj.size();
+        self.bytes_allocated += size;
+        self.allocated_since_gc = true;
+
+        let slot = Some(Slot {
+            marked: false,
+            size,
+            obj,
+        });
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.slots[index] = slot;
+                index
+            }
+            None => {
+                self.slots.push(slot);
+                self.slots.len() - 1
+            }
+        };
+
+        Gc::new(index)
+    }
+
+    // returns the string object with this content, allocating it only if
+    // no such string exists yet
+    pub fn intern(&mut self, s: &str) -> Gc<ObjString> {
+        if let Some(existing) = self.find_string(s) {
+            return existing;
+        }
+
+        let handle = self.alloc(ObjString::new(s));
+        self.strings.entry(hash_str(s)).or_default().push(handle);
+
+        handle
+    }
+    // the string object with this content if there is one
+    pub fn find_string(&self, s: &str) -> Option<Gc<ObjString>> {
+        self.strings
+            .get(&hash_str(s))
+            .and_then(|bucket| bucket.iter().find(|&&handle| &self.get(handle)[..] == s))
+            .cloned()
+    }
+
+    // the builtin class with this name, allocated by the first call
+    pub fn builtin_class(&mut self, name: &'static str) -> Gc<ObjClass> {
+        if let Some(&class) = self.builtin_classes.get(name) {
+            return class;
+        }
+
+        let class = self.alloc(ObjClass::new(ObjString::new(name)));
+        self.builtin_classes.insert(name, class);
+
+        class
+    }
+    pub fn find_builtin_class(&self, name: &str) -> Option<Gc<ObjClass>> {
+        self.builtin_classes.get(name).cloned()
+    }
+
+    pub fn get<T: HeapAllocated>(&self, handle: Gc<T>) -> &T {
+        self.slots[handle.index]
+            .as_ref()
+            .and_then(|slot| T::from_heap_obj(&slot.obj))
+            .expect("Dangling heap handle")
+    }
+
+    pub fn get_mut<T: HeapAllocated>(&mut self, handle: Gc<T>) -> &mut T {
+        self.slots[handle.index]
+            .as_mut()
+            .and_then(|slot| T::from_heap_obj_mut(&mut slot.obj))
+            .expect("Dangling heap handle")
+    }
+
+    // accounts for the object having grown or shrunk since it was allocated,
+    // called after adding fields, methods or elements to it
+    pub fn resized<T>(&mut self, handle: Gc<T>) {
+        let slot = self.slots[handle.index]
+            .as_mut()
+            .expect("Dangling heap handle");
+        let size = slot.obj.size();
+
+        self.bytes_allocated = self.bytes_allocated - slot.size + size;
+        slot.size = size;
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn should_collect(&self) -> bool {
+        (self.stress && self.allocated_since_gc) || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn mark_value(&mut self, value: &ValueOwned) {
+        trace_value(value, &mut self.gray);
+    }
+
+    pub fn mark_object(&mut self, obj: Obj) {
+        self.gray.push(obj.index());
+    }
+
+    pub fn mark<T>(&mut self, handle: Gc<T>) {
+        self.gray.push(handle.index);
+    }
+
+    // frees every object not reachable from the objects marked since the
+    // last collection
+    pub fn collect(&mut self) {
+        for class in self.builtin_classes.values() {
+            self.gray.push(class.index);
+        }
+        self.trace_references();
+        self.sweep();
+
+        self.next_gc = (self.bytes_allocated * HEAP_GROW_FACTOR).max(INITIAL_NEXT_GC);
+        self.allocated_since_gc = false;
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(index) = self.gray.pop() {
+            if let Some(ref mut slot) = self.slots[index] {
+                if slot.marked {
+                    continue;
+                }
+
+                slot.marked = true;
+                slot.obj.trace(&mut self.gray);
+            }
+        }
+    }
+
+    fn sweep(&mut self) {
+        for (index, entry) in self.slots.iter_mut().enumerate() {
+            let reachable = match entry {
+                Some(ref mut slot) => mem::replace(&mut slot.marked, false),
+                None => continue,
+            };
+
+            if !reachable {
+                if let Some(slot) = entry.take() {
+                    self.bytes_allocated -= slot.size;
+
+                    // the intern table does not keep strings alive
+                    if let HeapObj::String(ref s) = slot.obj {
+                        let bucket = self.strings.get_mut(&s.hash_code());
+                        if let Some(bucket) = bucket {
+                            bucket.retain(|handle| handle.index() != index);
+                            if bucket.is_empty() {
+                                self.strings.remove(&s.hash_code());
+                            }
+                        }
+                    }
+                }
+                self.free.push(index);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collect_frees_unmarked_objects() {
+        let mut heap = Heap::new();
+        let kept = heap.intern("kept");
+        heap.intern("garbage");
+
+        heap.mark(kept);
+        heap.collect();
+
+        assert_eq!(heap.object_count(), 1);
+        assert_eq!(&heap.get(kept)[..], "kept");
+    }
+
+    #[test]
+    fn intern_deduplicates_strings() {
+        let mut heap = Heap::new();
+        let a = heap.intern("name");
+        let b = heap.intern("name");
+
+        assert_eq!(a, b);
+        assert_ne!(a, heap.intern("other"));
+
+        // unreachable strings leave the table along with the heap
+        heap.collect();
+        assert_eq!(heap.object_count(), 0);
+        let c = heap.intern("name");
+        assert_eq!(&heap.get(c)[..], "name");
+        assert_eq!(heap.object_count(), 1);
+    }
+
+    #[test]
+    fn collect_traces_references() {
+        let mut heap = Heap::new();
+        let class = heap.alloc(ObjClass::new(ObjString::new("Point")));
+        let name = heap.intern("x");
+        let mut instance = ObjInstance::new(class);
+        instance
+            .fields
+            .insert(name, Value::Object(Obj::String(name)));
+        let instance = heap.alloc(instance);
+
+        heap.mark(instance);
+        heap.collect();
+
+        // the class and field name survive through the instance
+        assert_eq!(heap.object_count(), 3);
+        assert_eq!(&heap.get(heap.get(instance).class).name[..], "Point");
+        assert_eq!(heap.find_string("x"), Some(name));
+        heap.collect();
+        assert_eq!(heap.object_count(), 0);
+        assert_eq!(heap.find_string("x"), None);
+    }
+
+    #[test]
+    fn growth_counts_toward_collection() {
+        let mut heap = Heap::new();
+        let class = heap.alloc(ObjClass::new(ObjString::new("Point")));
+        let instance = heap.alloc(ObjInstance::new(class));
+        let before = heap.bytes_allocated();
+
+        for i in 0..100 {
+            let name = heap.intern(&i.to_string());
+            heap.get_mut(instance).fields.insert(name, Value::Nil);
+        }
+        let strings = heap.bytes_allocated() - before;
+        heap.resized(instance);
+        let fields = heap.bytes_allocated() - before - strings;
+        assert_eq!(fields, 100 * mem::size_of::<(Gc<ObjString>, ValueOwned)>());
+
+        // freeing the instance gives back what it grew to
+        heap.collect();
+        assert_eq!(heap.bytes_allocated(), 0);
+    }
+}
@@ -0,0 +1,574 @@
+use std::io::{self, Write};
+use std::ops::Deref;
+use std::rc::Rc;
+
+use compiler::{compile, compile_repl};
+use convert::{ConversionError, FromLox, ToLox};
+use gc::{Gc, Heap};
+use native::NativeFn;
+use object::{Obj, ObjFunction};
+use value::{Value, ValueOwned};
+use vm::{InterpretError, Result, VM};
+
+// compiled source, it can be run any number of times by the interpreter
+// that compiled it. Running it with another interpreter panics.
+pub struct Program {
+    function: Gc<ObjFunction>,
+    // id of the heap the function lives in
+    heap: usize,
+    // keeps the function from being collected while the program exists
+    _pin: Rc<()>,
+}
+
+// a value handed to the host, the objects it references are kept alive
+// until it is dropped. A ValueOwned taken out of it refers to the same
+// objects but does not keep them alive, it must not be used after the next
+// run once the Rooted is gone. Objects belong to the interpreter that handed
+// them out, converting a Rooted for another interpreter panics. A ValueOwned
+// taken out of it is not checked, passing it to another interpreter reads
+// whatever object that interpreter has in the same place.
+#[derive(Debug, Clone)]
+pub struct Rooted {
+    value: ValueOwned,
+    // id of the heap the objects live in
+    heap: usize,
+    // None for values that reference no object
+    _pin: Option<Rc<()>>,
+}
+
+impl Rooted {
+    pub fn value(&self) -> &ValueOwned {
+        &self.value
+    }
+    fn check_heap(&self, heap: &Heap) {
+        if self._pin.is_some() && heap.id() != self.heap {
+            panic!("Value used with an interpreter other than the one that created it");
+        }
+    }
+}
+
+impl Deref for Rooted {
+    type Target = ValueOwned;
+
+    fn deref(&self) -> &ValueOwned {
+        &self.value
+    }
+}
+
+impl ToLox for Rooted {
+    fn to_lox(self, heap: &mut Heap) -> ValueOwned {
+        self.check_heap(heap);
+        self.value
+    }
+}
+impl ToLox for &Rooted {
+    fn to_lox(self, heap: &mut Heap) -> ValueOwned {
+        self.check_heap(heap);
+        self.value.clone()
+    }
+}
+
+// compiles and runs Lox source for a host program. Globals persist from one
+// run to the next and print writes to W.
+pub struct Interpreter<W = io::Stdout> {
+    vm: VM<W>,
+}
+
+impl Interpreter<io::Stdout> {
+    pub fn new() -> Self {
+        Interpreter::with_output(io::stdout())
+    }
+}
+
+impl Default for Interpreter<io::Stdout> {
+    fn default() -> Self {
+        Interpreter::new()
+    }
+}
+
+impl<W> Interpreter<W> {
+    pub fn with_output(output: W) -> Self {
+        Interpreter {
+            vm: VM::new(output),
+        }
+    }
+
+    // the value stays valid across runs for as long as it is kept, even
+    // once the global is reassigned
+    pub fn get_global(&mut self, name: &str) -> Option<Rooted> {
+        let value = self.vm.global(name)?;
+        Some(self.root(value))
+    }
+    // defines the global or replaces its value, objects in a Lox value must
+    // live in this interpreter's heap
+    pub fn set_global<T: ToLox>(&mut self, name: &str, value: T) {
+        let value = value.to_lox(self.vm.heap_mut());
+        self.vm.set_global(name, value);
+    }
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        self.vm.define_native(name, arity, function);
+    }
+    // defines a set of natives such as LIST_NATIVES
+    pub fn define_natives(&mut self, natives: &[(&str, usize, NativeFn)]) {
+        self.vm.define_natives(natives);
+    }
+
+    pub fn set_stack_limit(&mut self, limit: usize) {
+        self.vm.set_stack_limit(limit);
+    }
+    pub fn set_trace(&mut self, trace: Option<Box<dyn Write>>) {
+        self.vm.set_trace(trace);
+    }
+
+    pub fn output(&self) -> &W {
+        self.vm.output()
+    }
+    pub fn output_mut(&mut self) -> &mut W {
+        self.vm.output_mut()
+    }
+    pub fn into_output(self) -> W {
+        self.vm.into_output()
+    }
+
+    // the converted value stays valid across runs for as long as it is kept
+    pub fn to_lox<T: ToLox>(&mut self, value: T) -> Rooted {
+        let value = value.to_lox(self.vm.heap_mut());
+        self.root(value)
+    }
+    pub fn from_lox<'h, T: FromLox<'h>>(
+        &'h self,
+        value: &ValueOwned,
+    ) -> ::std::result::Result<T, ConversionError> {
+        T::from_lox(value, self.vm.heap())
+    }
+
+    // objects referenced by values of this interpreter live here
+    pub fn heap(&self) -> &Heap {
+        self.vm.heap()
+    }
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        self.vm.heap_mut()
+    }
+
+    fn root(&mut self, value: ValueOwned) -> Rooted {
+        let pin = match value {
+            Value::Object(obj) => Some(self.vm.pin(obj)),
+            _ => None,
+        };
+
+        Rooted {
+            value,
+            heap: self.vm.heap().id(),
+            _pin: pin,
+        }
+    }
+
+    fn program(&mut self, function: Gc<ObjFunction>) -> Program {
+        let pin = self.vm.pin(Obj::Function(function));
+        Program {
+            function,
+            heap: self.vm.heap().id(),
+            _pin: pin,
+        }
+    }
+    fn check_program(&self, program: &Program) {
+        if program.heap != self.vm.heap().id() {
+            panic!("Program run by an interpreter other than the one that compiled it");
+        }
+    }
+}
+
+impl<W: Write> Interpreter<W> {
+    pub fn eval(&mut self, source: &str) -> Result<()> {
+        let program = self.compile(source)?;
+        self.run(&program)
+    }
+
+    pub fn compile(&mut self, source: &str) -> Result<Program> {
+        let function = compile(source, self.vm.heap_mut()).map_err(InterpretError::CompileError)?;
+        Ok(self.program(function))
+    }
+    // compiles for interactive use, top level expression statements print
+    // their value
+    pub fn compile_repl(&mut self, source: &str) -> Result<Program> {
+        let function =
+            compile_repl(source, self.vm.heap_mut()).map_err(InterpretError::CompileError)?;
+        Ok(self.program(function))
+    }
+
+    pub fn run(&mut self, program: &Program) -> Result<()> {
+        self.check_program(program);
+        self.vm.interpret(program.function)
+    }
+
+    // calls a function, method or class defined by a program that already
+    // ran, or a native, and returns its result. The result stays valid across
+    // runs for as long as it is kept
+    pub fn call(&mut self, callee: &ValueOwned, args: &[ValueOwned]) -> Result<Rooted> {
+        let result = self.vm.call(callee, args)?;
+        Ok(self.root(result))
+    }
+
+    // writes the bytecode of the program to the output
+    pub fn disassemble(&mut self, program: &Program) -> io::Result<()> {
+        self.check_program(program);
+        self.vm.disassemble(program.function)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use native::{NativeContext, NativeError, LIST_NATIVES};
+    use value::Value;
+
+    type NativeResult = ::std::result::Result<ValueOwned, NativeError>;
+
+    fn output(interpreter: Interpreter<Vec<u8>>) -> String {
+        String::from_utf8(interpreter.into_output()).unwrap()
+    }
+
+    #[test]
+    fn globals_persist_between_runs() {
+        let mut interpreter = Interpreter::with_output(Vec::new());
+        interpreter.eval("var a = 1;").unwrap();
+        interpreter.set_global("b", Value::from(2.0));
+        interpreter.eval("print a + b; a = 10;").unwrap();
+
+        assert_eq!(
+            interpreter.get_global("a").as_deref(),
+            Some(&Value::from(10.0))
+        );
+        assert!(interpreter.get_global("c").is_none());
+        assert_eq!(output(interpreter), "3\n");
+    }
+
+    fn join(ctx: &mut dyn NativeContext, args: &[ValueOwned]) -> NativeResult {
+        let parts: Vec<&str> = FromLox::from_lox(&args[0], ctx.heap())?;
+        let separator: Option<&str> = FromLox::from_lox(&args[1], ctx.heap())?;
+
+        let joined = parts.join(separator.unwrap_or(""));
+        Ok(joined.to_lox(ctx.heap_mut()))
+    }
+
+    #[test]
+    fn converted_values_survive_collections() {
+        let mut interpreter = Interpreter::with_output(Vec::new());
+        interpreter.heap_mut().set_stress(true);
+
+        interpreter.define_natives(&LIST_NATIVES);
+
+        let words = interpreter.to_lox(vec!["a", "b"]);
+        interpreter.eval("var s = \"x\" + \"y\";").unwrap();
+        interpreter.set_global("words", &words);
+        interpreter
+            .eval("print len(words); print get(words, 1);")
+            .unwrap();
+
+        assert_eq!(output(interpreter), "2\n\"b\"\n");
+    }
+
+    #[test]
+    fn converts_host_values() {
+        let mut interpreter = Interpreter::with_output(Vec::new());
+        interpreter.define_native("join", 2, join);
+        interpreter.set_global("words", vec!["a", "b", "c"]);
+        interpreter.set_global("limit", Some(2u32));
+        let error = interpreter
+            .eval("print len(words); var joined = join(words, \"-\"); print join(words, 1);")
+            .unwrap_err();
+        assert_eq!(
+            error.to_string().lines().next(),
+            Some("expected a string, found 1")
+        );
+
+        let joined = interpreter.get_global("joined").unwrap();
+        assert_eq!(interpreter.from_lox::<&str>(&joined), Ok("a-b-c"));
+        let limit = interpreter.get_global("limit").unwrap();
+        assert_eq!(interpreter.from_lox::<u32>(&limit), Ok(2));
+        assert_eq!(output(interpreter), "3\n");
+    }
+
+    #[test]
+    fn scripts_read_and_build_host_lists() {
+        let mut interpreter = Interpreter::with_output(Vec::new());
+        interpreter.define_natives(&LIST_NATIVES);
+        interpreter.set_global("words", vec!["a", "b"]);
+        interpreter
+            .eval(
+                "
+                print get(words, 1);
+                var lengths = list();
+                for (var i = 0; i < len(words); i = i + 1) {
+                    push(lengths, len(get(words, i) + \"!\"));
+                }
+                ",
+            )
+            .unwrap();
+
+        let lengths = interpreter.get_global("lengths").unwrap();
+        assert_eq!(interpreter.from_lox::<Vec<u32>>(&lengths), Ok(vec![2, 2]));
+        assert_eq!(output(interpreter), "\"b\"\n");
+    }
+
+    #[test]
+    fn errors_are_typed() {
+        let mut interpreter = Interpreter::with_output(Vec::new());
+
+        match interpreter.eval("print ;\nvar;") {
+            Err(InterpretError::CompileError(errors)) => assert_eq!(errors.len(), 2),
+            other => panic!("Expected compile errors, got {:?}", other),
+        }
+        match interpreter.eval("print nil + 1;") {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.line, 1),
+            other => panic!("Expected a runtime error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Program run by an interpreter other than the one that compiled it")]
+    fn programs_only_run_in_their_interpreter() {
+        let mut first = Interpreter::with_output(Vec::<u8>::new());
+        let mut second = Interpreter::with_output(Vec::<u8>::new());
+
+        let program = first.compile("print 1;").unwrap();
+        let _ = second.run(&program);
+    }
+
+    #[test]
+    #[should_panic(expected = "Value used with an interpreter other than the one that created it")]
+    fn values_only_convert_for_their_interpreter() {
+        let mut first = Interpreter::with_output(Vec::<u8>::new());
+        let mut second = Interpreter::with_output(Vec::<u8>::new());
+
+        // values without objects are not tied to an interpreter
+        let number = first.to_lox(1.0);
+        second.set_global("n", number);
+
+        let s = first.to_lox("s");
+        second.set_global("s", s);
+    }
+
+    #[test]
+    fn programs_survive_collections() {
+        let mut interpreter = Interpreter::with_output(Vec::new());
+        interpreter.heap_mut().set_stress(true);
+
+        let program = interpreter
+            .compile("var count = count + 1; print \"run \" + str(count);")
+            .unwrap();
+        interpreter.set_global("count", Value::from(0.0));
+        for _ in 0..3 {
+            interpreter.run(&program).unwrap();
+        }
+
+        assert_eq!(output(interpreter), "\"run 1\"\n\"run 2\"\n\"run 3\"\n");
+    }
+
+    #[test]
+    fn globals_handed_out_survive_collections() {
+        let mut interpreter = Interpreter::with_output(Vec::new());
+        interpreter.heap_mut().set_stress(true);
+
+        interpreter.eval("fun f() { return \"from f\"; }").unwrap();
+        let f = interpreter.get_global("f").unwrap();
+        // nothing in the script references f anymore, the slots it used
+        // are up for reuse
+        interpreter
+            .eval("f = nil; fun g() { return \"from g\"; } var s = g() + g();")
+            .unwrap();
+        interpreter.set_global("f", &f);
+        interpreter.eval("print f();").unwrap();
+
+        assert_eq!(output(interpreter), "\"from f\"\n");
+    }
+
+    fn apply(ctx: &mut dyn NativeContext, args: &[ValueOwned]) -> NativeResult {
+        Ok(ctx.call(&args[0], &args[1..])?)
+    }
+
+    // calls the function and yields nil if it fails
+    fn attempt(ctx: &mut dyn NativeContext, args: &[ValueOwned]) -> NativeResult {
+        Ok(ctx.call(&args[0], &[]).unwrap_or(Value::Nil))
+    }
+
+    // calls the function and raises the message of its error as its own
+    fn reraise(ctx: &mut dyn NativeContext, args: &[ValueOwned]) -> NativeResult {
+        ctx.call(&args[0], &[])
+            .map_err(|e| NativeError::Message(e.message))
+    }
+
+    // calls the function twice and joins both results
+    fn twice(ctx: &mut dyn NativeContext, args: &[ValueOwned]) -> NativeResult {
+        let first = ctx.call(&args[0], &[])?;
+        let second = ctx.call(&args[0], &[])?;
+
+        let joined = {
+            let heap = ctx.heap();
+            let first: &str = FromLox::from_lox(&first, heap)?;
+            let second: &str = FromLox::from_lox(&second, heap)?;
+            format!("{}{}", first, second)
+        };
+        Ok(joined.to_lox(ctx.heap_mut()))
+    }
+
+    fn with_natives() -> Interpreter<Vec<u8>> {
+        let mut interpreter = Interpreter::with_output(Vec::new());
+        interpreter.define_native("apply", 2, apply);
+        interpreter.define_native("attempt", 1, attempt);
+        interpreter.define_native("twice", 1, twice);
+        interpreter.define_native("reraise", 1, reraise);
+
+        interpreter
+    }
+
+    #[test]
+    fn host_calls_script_functions() {
+        let mut interpreter = with_natives();
+        interpreter
+            .eval("fun add(a, b) { return a + b; } class Point { init(x) { this.x = x; } }")
+            .unwrap();
+
+        let add = interpreter.get_global("add").unwrap();
+        let args = [Value::from(1.0), Value::from(2.0)];
+        assert_eq!(*interpreter.call(&add, &args).unwrap(), Value::from(3.0));
+
+        let point = interpreter.get_global("Point").unwrap();
+        let point = interpreter.call(&point, &[Value::from(4.0)]).unwrap();
+        interpreter.set_global("p", point);
+        interpreter.eval("print p.x;").unwrap();
+
+        let error = interpreter.call(&add, &[Value::Nil, Value::from(1.0)]);
+        assert!(error.is_err());
+        assert_eq!(*interpreter.call(&add, &args).unwrap(), Value::from(3.0));
+        assert_eq!(output(interpreter), "4\n");
+    }
+
+    #[test]
+    fn results_handed_out_survive_collections() {
+        let mut interpreter = Interpreter::with_output(Vec::new());
+        interpreter.heap_mut().set_stress(true);
+        interpreter
+            .eval("class P { init(x) { this.x = x; } }")
+            .unwrap();
+
+        let class = interpreter.get_global("P").unwrap();
+        let p = interpreter.call(&class, &[Value::from(1.0)]).unwrap();
+        // would take over the slot of the first instance if it was collected
+        interpreter.eval("var q = P(2);").unwrap();
+        interpreter.set_global("p", p);
+        interpreter.eval("print p.x;").unwrap();
+
+        assert_eq!(output(interpreter), "1\n");
+    }
+
+    #[test]
+    fn natives_call_back_into_scripts() {
+        let mut interpreter = with_natives();
+        interpreter
+            .eval(
+                "
+                fun inc(x) { return x + 1; }
+                fun outer(x) { return apply(inc, x) + 10; }
+                print apply(outer, 1);
+                ",
+            )
+            .unwrap();
+        assert_eq!(output(interpreter), "12\n");
+    }
+
+    #[test]
+    fn errors_of_nested_calls_keep_their_trace() {
+        let mut interpreter = with_natives();
+        let error = interpreter
+            .eval(
+                "
+                fun bad(x) {
+                    return x + 1;
+                }
+                print attempt(bad);
+                fun run() {
+                    return apply(bad, nil);
+                }
+                run();
+                ",
+            )
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Operands must be two numbers or two strings\n\
+             [line 3] in bad()\n\
+             [line 7] in run()\n\
+             [line 9] in script"
+        );
+        assert_eq!(output(interpreter), "nil\n");
+    }
+
+    #[test]
+    fn natives_can_raise_their_own_errors_for_nested_calls() {
+        let mut interpreter = with_natives();
+        let error = interpreter
+            .eval(
+                "
+                fun bad() {
+                    return nil + 1;
+                }
+                reraise(bad);
+                ",
+            )
+            .unwrap_err();
+
+        // the same message, raised where the native was called
+        assert_eq!(
+            error.to_string(),
+            "Operands must be two numbers or two strings\n\
+             [line 5] in script"
+        );
+    }
+
+    #[test]
+    fn results_of_nested_calls_survive_collections() {
+        let mut interpreter = with_natives();
+        interpreter.heap_mut().set_stress(true);
+        interpreter
+            .eval(
+                "
+                var count = 0;
+                fun next() { count = count + 1; return \"s\" + str(count); }
+                print twice(next);
+                ",
+            )
+            .unwrap();
+
+        assert_eq!(output(interpreter), "\"s1s2\"\n");
+    }
+
+    #[test]
+    fn results_of_nested_calls_survive_collections_when_the_host_calls() {
+        let mut interpreter = with_natives();
+        interpreter.heap_mut().set_stress(true);
+        interpreter
+            .eval("var count = 0; fun next() { count = count + 1; return \"s\" + str(count); }")
+            .unwrap();
+
+        let twice = interpreter.get_global("twice").unwrap();
+        let next = interpreter.get_global("next").unwrap();
+        let joined = interpreter.call(&twice, &[next.value().clone()]).unwrap();
+
+        assert_eq!(interpreter.from_lox::<&str>(&joined).unwrap(), "s1s2");
+    }
+
+    #[test]
+    fn natives_calling_back_are_bounded() {
+        let mut interpreter = with_natives();
+        match interpreter.eval("fun f(n) { return apply(f, n + 1); } f(0);") {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "Stack overflow"),
+            other => panic!("Expected a stack overflow, got {:?}", other),
+        }
+        // the vm is still usable afterwards
+        interpreter.eval("print apply(str, 1);").unwrap();
+
+        assert_eq!(output(interpreter), "\"1\"\n");
+    }
+}
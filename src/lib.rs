@@ -0,0 +1,27 @@
+#![allow(non_camel_case_types)]
+
+extern crate num;
+
+mod chunk;
+mod common;
+mod compiler;
+mod convert;
+mod gc;
+mod interpreter;
+mod native;
+mod object;
+pub mod scanner;
+mod value;
+mod vm;
+
+pub use compiler::{CompileError, CompileErrorPayload};
+pub use convert::{ConversionError, FromLox, ToLox};
+pub use gc::{Gc, Heap};
+pub use interpreter::{Interpreter, Program, Rooted};
+pub use native::{NativeContext, NativeError, NativeFn, LIST_NATIVES};
+pub use object::{
+    Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjList, ObjNative,
+    ObjString, ObjUpvalue,
+};
+pub use value::{Value, ValueOwned};
+pub use vm::{InterpretError, RuntimeError, TraceFrame};
@@ -1,42 +1,243 @@
-#![allow(non_camel_case_types)]
+extern crate loxvm;
+extern crate rustyline;
 
-extern crate arraydeque;
-extern crate num;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::process;
 
-use std::io;
+mod repl;
 
-mod chunk;
-mod common;
-mod compiler;
-mod object;
-mod scanner;
-mod value;
-mod vm;
+use loxvm::{InterpretError, Interpreter};
 
-use chunk::Chunk;
-use common::*;
-use compiler::compile;
-use scanner::Scanner;
-use scanner::TokenType;
-use vm::VM;
+const USAGE: &str = "Usage: loxvm [--disassemble] [--trace] [script | -e code | -]";
+
+// exit codes from sysexits.h
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_NOINPUT: i32 = 66;
+const EX_SOFTWARE: i32 = 70;
+const EX_IOERR: i32 = 74;
+
+#[derive(Debug, PartialEq)]
+enum Input {
+    File(String),
+    Inline(String),
+    Stdin,
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Input::File(ref path) => write!(f, "{}", path),
+            Input::Inline(_) => write!(f, "-e"),
+            Input::Stdin => write!(f, "stdin"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    // None starts the repl
+    input: Option<Input>,
+    // print the bytecode of the script before running it
+    disassemble: bool,
+    // trace execution to stderr
+    trace: bool,
+}
+
+enum Command {
+    Run(Options),
+    Help,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut input = None;
+    let mut disassemble = false;
+    let mut trace = false;
+
+    while let Some(arg) = args.next() {
+        let next = match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--disassemble" => {
+                disassemble = true;
+                continue;
+            }
+            "--trace" => {
+                trace = true;
+                continue;
+            }
+            "-e" => match args.next() {
+                Some(code) => Input::Inline(code),
+                None => return Err("-e expects an argument".to_string()),
+            },
+            "-" => Input::Stdin,
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ => Input::File(arg),
+        };
+
+        if input.is_some() {
+            return Err("only one script can be run".to_string());
+        }
+        input = Some(next);
+    }
+
+    Ok(Command::Run(Options {
+        input,
+        disassemble,
+        trace,
+    }))
+}
+
+fn read_source(input: &Input) -> io::Result<String> {
+    match input {
+        Input::File(ref path) => fs::read_to_string(path),
+        Input::Inline(ref code) => Ok(code.clone()),
+        Input::Stdin => {
+            let mut source = String::new();
+            io::stdin().read_to_string(&mut source)?;
+            Ok(source)
+        }
+    }
+}
+
+// blanks out a leading #! line, its newline is kept so that line numbers
+// still match the file
+fn skip_shebang(source: &str) -> &str {
+    if !source.starts_with("#!") {
+        return source;
+    }
+
+    match source.find('\n') {
+        Some(end) => &source[end..],
+        None => "",
+    }
+}
+
+// runs the script or the repl and returns the process exit code
+fn run(options: &Options) -> i32 {
+    let input = match options.input {
+        Some(ref input) => input,
+        None => {
+            return match repl::run(options.disassemble, options.trace) {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("loxvm: {}", e);
+                    EX_IOERR
+                }
+            }
+        }
+    };
+
+    let source = match read_source(input) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("loxvm: cannot read {}: {}", input, e);
+            return match *input {
+                Input::File(_) => EX_NOINPUT,
+                _ => EX_IOERR,
+            };
+        }
+    };
+    let source = skip_shebang(&source);
+
+    let mut interpreter = Interpreter::new();
+    if options.trace {
+        interpreter.set_trace(Some(Box::new(io::stderr())));
+    }
+    let program = match interpreter.compile(source) {
+        Ok(program) => program,
+        Err(e) => {
+            report(source, &e);
+            return EX_DATAERR;
+        }
+    };
+
+    if options.disassemble {
+        if let Err(e) = interpreter.disassemble(&program) {
+            eprintln!("loxvm: failed to write disassembly: {}", e);
+            return EX_IOERR;
+        }
+    }
+
+    match interpreter.run(&program) {
+        Ok(()) => 0,
+        Err(e) => {
+            report(source, &e);
+            EX_SOFTWARE
+        }
+    }
+}
+
+// prints the error to stderr, compile errors point into source
+fn report(source: &str, error: &InterpretError) {
+    match error {
+        InterpretError::CompileError(ref errors) => {
+            let color = io::stderr().is_terminal();
+            for e in errors {
+                eprint!("{}", e.render(source, color));
+            }
+        }
+        InterpretError::RuntimeError(ref e) => eprintln!("{}", e),
+    }
+}
 
 fn main() {
-    let stdout = io::stdout();
-    let mut chunk = Chunk::new();
-
-    let s = "var y = 22;
-    var x = 10; 
-    x = y * 3 - 2;
-    print x+y;";
-    let result = compile(s, &mut chunk);
-
-    match result {
-        Ok(_) => {
-            let mut vm = VM::new(&chunk, stdout);
-            vm.disassemble();
-            println!("\n == Program Output == \n");
-            vm.interpret();
-        }
-        Err(e) => println!("{:?}", e),
+    match parse_args(env::args().skip(1)) {
+        Ok(Command::Run(options)) => process::exit(run(&options)),
+        Ok(Command::Help) => println!("{}", USAGE),
+        Err(message) => {
+            eprintln!("loxvm: {}\n{}", message, USAGE);
+            process::exit(EX_USAGE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        match parse_args(args.iter().map(|arg| arg.to_string()))? {
+            Command::Run(options) => Ok(options),
+            Command::Help => Err("help".to_string()),
+        }
+    }
+
+    #[test]
+    fn parses_inputs_and_flags() {
+        assert_eq!(
+            parse(&["--disassemble", "main.lox"]),
+            Ok(Options {
+                input: Some(Input::File("main.lox".to_string())),
+                disassemble: true,
+                trace: false,
+            })
+        );
+        assert_eq!(
+            parse(&["-e", "print 1;", "--trace"]),
+            Ok(Options {
+                input: Some(Input::Inline("print 1;".to_string())),
+                disassemble: false,
+                trace: true,
+            })
+        );
+        assert_eq!(
+            parse(&["-"]).map(|options| options.input),
+            Ok(Some(Input::Stdin))
+        );
+        assert_eq!(parse(&[]).map(|options| options.input), Ok(None));
+
+        assert!(parse(&["-e"]).is_err());
+        assert!(parse(&["--verbose", "main.lox"]).is_err());
+        assert!(parse(&["a.lox", "b.lox"]).is_err());
+    }
+
+    #[test]
+    fn skips_shebang_line() {
+        assert_eq!(skip_shebang("#!/usr/bin/env loxvm\nprint 1;"), "\nprint 1;");
+        assert_eq!(skip_shebang("#!/usr/bin/env loxvm"), "");
+        assert_eq!(skip_shebang("print 1;"), "print 1;");
     }
 }
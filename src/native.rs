@@ -0,0 +1,178 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use convert::{as_list, new_list, ConversionError};
+use gc::{Gc, Heap};
+use object::{Obj, ObjList};
+use value::{Value, ValueOwned};
+use vm::RuntimeError;
+
+// signature of host functions callable from scripts, an Err aborts the
+// script with a runtime error. Objects created by a native must be allocated
+// in the heap of its context.
+pub type NativeFn = fn(&mut dyn NativeContext, &[ValueOwned]) -> Result<ValueOwned, NativeError>;
+
+// why a native gave up
+#[derive(Debug, Clone, PartialEq)]
+pub enum NativeError {
+    // raised as a runtime error at the call of the native
+    Message(String),
+    // a call the native made failed, raised as it is
+    Call(RuntimeError),
+}
+
+impl From<String> for NativeError {
+    fn from(message: String) -> NativeError {
+        NativeError::Message(message)
+    }
+}
+impl From<&str> for NativeError {
+    fn from(message: &str) -> NativeError {
+        NativeError::Message(message.to_string())
+    }
+}
+// lets natives convert their arguments with ?
+impl From<ConversionError> for NativeError {
+    fn from(e: ConversionError) -> NativeError {
+        NativeError::Message(e.to_string())
+    }
+}
+// lets natives pass on the errors of their calls with ?
+impl From<RuntimeError> for NativeError {
+    fn from(e: RuntimeError) -> NativeError {
+        NativeError::Call(e)
+    }
+}
+
+// the vm as seen by a native it runs
+pub trait NativeContext {
+    fn heap(&self) -> &Heap;
+    fn heap_mut(&mut self) -> &mut Heap;
+    // calls back into the program and returns the result or the error it
+    // raised. Objects the native created and did not pass as arguments may
+    // be collected during the call, returned values stay alive until the
+    // native returns.
+    fn call(
+        &mut self,
+        callee: &ValueOwned,
+        args: &[ValueOwned],
+    ) -> Result<ValueOwned, RuntimeError>;
+}
+
+// natives every VM starts with: name, arity, function
+pub const DEFAULT_NATIVES: [(&str, usize, NativeFn); 5] = [
+    ("clock", 0, clock),
+    ("len", 1, len),
+    ("str", 1, str),
+    ("num", 1, num),
+    ("type", 1, type_of),
+];
+
+// natives for hosts that hand lists to scripts, defined on request
+pub const LIST_NATIVES: [(&str, usize, NativeFn); 4] = [
+    ("list", 0, list),
+    ("push", 2, push),
+    ("get", 2, get),
+    ("set", 3, set),
+];
+
+// seconds since the unix epoch
+fn clock(_ctx: &mut dyn NativeContext, _args: &[ValueOwned]) -> Result<ValueOwned, NativeError> {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+
+    Ok(Value::from(elapsed.as_secs_f64()))
+}
+
+// number of characters in a string or of elements in a list
+fn len(ctx: &mut dyn NativeContext, args: &[ValueOwned]) -> Result<ValueOwned, NativeError> {
+    let heap = ctx.heap();
+    match args[0] {
+        Value::Object(Obj::String(s)) => Ok(Value::from(heap.get(s).chars().count() as f64)),
+        Value::Object(Obj::List(list)) => Ok(Value::from(heap.get(list).elements.len() as f64)),
+        _ => Err("len() expects a string or a list".into()),
+    }
+}
+
+// string representation of any value, without quotes around strings
+fn str(ctx: &mut dyn NativeContext, args: &[ValueOwned]) -> Result<ValueOwned, NativeError> {
+    match args[0] {
+        Value::Object(Obj::String(_)) => Ok(args[0].clone()),
+        ref value => {
+            let s = value.display(ctx.heap()).to_string();
+            Ok(Value::Object(Obj::String(ctx.heap_mut().intern(&s))))
+        }
+    }
+}
+
+// parses a string into a number, numbers are returned as is
+fn num(ctx: &mut dyn NativeContext, args: &[ValueOwned]) -> Result<ValueOwned, NativeError> {
+    let heap = ctx.heap();
+    match args[0] {
+        Value::Number(_) => Ok(args[0].clone()),
+        Value::Object(Obj::String(s)) => heap
+            .get(s)
+            .trim()
+            .parse::<f64>()
+            .map(Value::from)
+            .map_err(|_| format!("num() cannot convert {} to a number", heap.get(s)).into()),
+        _ => Err("num() expects a number or a string".into()),
+    }
+}
+
+// name of the type of a value
+fn type_of(ctx: &mut dyn NativeContext, args: &[ValueOwned]) -> Result<ValueOwned, NativeError> {
+    let name = args[0].type_name();
+    Ok(Value::Object(Obj::String(ctx.heap_mut().intern(name))))
+}
+
+// lists are converted to and from host vectors, see convert
+fn list(ctx: &mut dyn NativeContext, _args: &[ValueOwned]) -> Result<ValueOwned, NativeError> {
+    Ok(new_list(ctx.heap_mut(), Vec::new()))
+}
+
+// appends the value and returns the list
+fn push(ctx: &mut dyn NativeContext, args: &[ValueOwned]) -> Result<ValueOwned, NativeError> {
+    let list = list_arg("push", &args[0])?;
+    let heap = ctx.heap_mut();
+    heap.get_mut(list).elements.push(args[1].clone());
+    heap.resized(list);
+
+    Ok(args[0].clone())
+}
+
+// element at an index
+fn get(ctx: &mut dyn NativeContext, args: &[ValueOwned]) -> Result<ValueOwned, NativeError> {
+    let heap = ctx.heap();
+    let list = heap.get(list_arg("get", &args[0])?);
+    let index = index_arg("get", list, &args[1], heap)?;
+
+    Ok(list.elements[index].clone())
+}
+
+// replaces the element at an index and returns the value
+fn set(ctx: &mut dyn NativeContext, args: &[ValueOwned]) -> Result<ValueOwned, NativeError> {
+    let list = list_arg("set", &args[0])?;
+    let index = index_arg("set", ctx.heap().get(list), &args[1], ctx.heap())?;
+    ctx.heap_mut().get_mut(list).elements[index] = args[2].clone();
+
+    Ok(args[2].clone())
+}
+
+fn list_arg(name: &str, value: &ValueOwned) -> Result<Gc<ObjList>, String> {
+    as_list(value).ok_or_else(|| format!("{}() expects a list", name))
+}
+
+// index of an existing element of the list
+fn index_arg(name: &str, list: &ObjList, value: &ValueOwned, heap: &Heap) -> Result<usize, String> {
+    let length = list.elements.len();
+    match *value {
+        Value::Number(n) if n.fract() == 0.0 && n >= 0.0 && n < length as f64 => Ok(n as usize),
+        _ => Err(format!(
+            "{}() index {} is out of range for a list of length {}",
+            name,
+            value.display(heap),
+            length
+        )),
+    }
+}
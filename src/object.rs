@@ -1,24 +1,222 @@
+use std::collections::HashMap;
 use std::convert::Into;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
 use std::ops::Deref;
 
-#[derive(Clone)]
+use chunk::Chunk;
+use gc::{Gc, Heap};
+use native::NativeFn;
+use value::{Value, ValueOwned};
+
+// handle to an object living in the vm heap
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Obj {
-    Function,
-    String(Box<ObjString>),
+    BoundMethod(Gc<ObjBoundMethod>),
+    Class(Gc<ObjClass>),
+    Closure(Gc<ObjClosure>),
+    Function(Gc<ObjFunction>),
+    Instance(Gc<ObjInstance>),
+    List(Gc<ObjList>),
+    Native(Gc<ObjNative>),
+    String(Gc<ObjString>),
+}
+
+impl Obj {
+    pub fn index(&self) -> usize {
+        match self {
+            Obj::BoundMethod(handle) => handle.index(),
+            Obj::Class(handle) => handle.index(),
+            Obj::Closure(handle) => handle.index(),
+            Obj::Function(handle) => handle.index(),
+            Obj::Instance(handle) => handle.index(),
+            Obj::List(handle) => handle.index(),
+            Obj::Native(handle) => handle.index(),
+            Obj::String(handle) => handle.index(),
+        }
+    }
+    // objects only know their contents through the heap they live in
+    pub fn fmt_in(&self, heap: &Heap, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Obj::BoundMethod(bound) => {
+                let method = heap.get(heap.get(bound).method);
+                write!(f, "{}", heap.get(method.function))
+            }
+            Obj::Class(class) => write!(f, "{}", heap.get(class)),
+            Obj::Closure(closure) => write!(f, "{}", heap.get(heap.get(closure).function)),
+            Obj::Function(fun) => write!(f, "{}", heap.get(fun)),
+            Obj::Instance(instance) => {
+                let class = heap.get(heap.get(instance).class);
+                write!(f, "{} instance", &*class.name)
+            }
+            // elements are left out, a list may contain itself
+            Obj::List(list) => write!(f, "<list of {}>", heap.get(list).elements.len()),
+            Obj::Native(native) => write!(f, "{}", heap.get(native)),
+            Obj::String(s) => write!(f, "{}", heap.get(s)),
+        }
+    }
+}
+
+pub struct ObjFunction {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    // None for top level code
+    pub name: Option<ObjString>,
 }
-impl fmt::Display for Obj {
+impl fmt::Display for ObjFunction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "<fn {}>", &**name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<&str>) -> Self {
+        ObjFunction {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name: name.map(ObjString::new),
+        }
+    }
+    // disassembles this function followed by every function nested in it
+    pub fn disassemble<W: Write>(&self, heap: &Heap, write_to: &mut W) -> io::Result<()> {
+        writeln!(write_to, "== {} ==", self)?;
+        self.chunk.disassemble(heap, write_to)?;
+
+        for constant in self.chunk.constants() {
+            if let Value::Object(Obj::Function(fun)) = *constant {
+                writeln!(write_to)?;
+                heap.get(fun).disassemble(heap, write_to)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub struct ObjNative {
+    pub name: ObjString,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+impl fmt::Display for ObjNative {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<native fn {}>", &*self.name)
+    }
+}
+
+impl ObjNative {
+    pub fn new(name: &str, arity: usize, function: NativeFn) -> Self {
+        ObjNative {
+            name: ObjString::new(name),
+            arity,
+            function,
+        }
+    }
+}
+
+// a function together with the variables it captured
+pub struct ObjClosure {
+    pub function: Gc<ObjFunction>,
+    pub upvalues: Vec<Gc<ObjUpvalue>>,
+}
+
+impl ObjClosure {
+    pub fn new(function: Gc<ObjFunction>, upvalues: Vec<Gc<ObjUpvalue>>) -> Self {
+        ObjClosure { function, upvalues }
+    }
+}
+
+// a method closure paired with the instance it was accessed on
+pub struct ObjBoundMethod {
+    pub receiver: ValueOwned,
+    pub method: Gc<ObjClosure>,
+}
+
+impl ObjBoundMethod {
+    pub fn new(receiver: ValueOwned, method: Gc<ObjClosure>) -> Self {
+        ObjBoundMethod { receiver, method }
+    }
+}
+
+pub enum ObjUpvalue {
+    // captured variable still lives in this stack slot
+    Open(usize),
+    // captured variable outlived its stack frame
+    Closed(ValueOwned),
+}
+
+impl ObjUpvalue {
+    pub fn open_slot(&self) -> Option<usize> {
         match self {
-            Obj::Function => write!(f, "[function]"),
-            Obj::String(ref s) => s.fmt(f),
+            ObjUpvalue::Open(slot) => Some(*slot),
+            ObjUpvalue::Closed(_) => None,
+        }
+    }
+}
+
+pub struct ObjClass {
+    pub name: ObjString,
+    pub methods: HashMap<Gc<ObjString>, Gc<ObjClosure>>,
+}
+impl fmt::Display for ObjClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", &*self.name)
+    }
+}
+
+impl ObjClass {
+    pub fn new(name: ObjString) -> Self {
+        ObjClass {
+            name,
+            methods: HashMap::new(),
         }
     }
+    pub fn find_method(&self, name: Gc<ObjString>) -> Option<Gc<ObjClosure>> {
+        self.methods.get(&name).cloned()
+    }
+}
+
+pub struct ObjInstance {
+    pub class: Gc<ObjClass>,
+    pub fields: HashMap<Gc<ObjString>, ValueOwned>,
+}
+
+impl ObjInstance {
+    pub fn new(class: Gc<ObjClass>) -> Self {
+        ObjInstance {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+// elements of a list created by the host or the list natives
+pub struct ObjList {
+    pub elements: Vec<ValueOwned>,
+}
+
+impl ObjList {
+    pub fn new(elements: Vec<ValueOwned>) -> Self {
+        ObjList { elements }
+    }
 }
 
 #[derive(Eq, PartialEq, Clone)]
 pub struct ObjString {
     inner: String,
+    // computed once, used by the intern table and hash maps
+    hash: u64,
+}
+impl Hash for ObjString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
 }
 impl fmt::Display for ObjString {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -31,10 +229,21 @@ impl fmt::Display for ObjString {
 impl ObjString {
     pub fn new<S: Into<String>>(s: S) -> Self {
         let inner = s.into();
-        ObjString { inner }
+        let hash = hash_str(&inner);
+        ObjString { inner, hash }
+    }
+    pub fn hash_code(&self) -> u64 {
+        self.hash
     }
 }
 
+// FNV-1a
+pub fn hash_str(s: &str) -> u64 {
+    s.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
 impl Deref for ObjString {
     type Target = str;
 
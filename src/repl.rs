@@ -0,0 +1,146 @@
+use std::io::{self, Write};
+
+use loxvm::scanner::{Scanner, TokenType};
+use loxvm::Interpreter;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use super::report;
+
+const PROMPT: &str = "> ";
+// shown while an entry spans several lines
+const CONTINUATION_PROMPT: &str = "... ";
+
+// reads entries until end of input, each one is compiled on its own and run
+// by the same interpreter so that globals defined by an entry stay visible
+// to the following ones
+pub fn run(disassemble: bool, trace: bool) -> rustyline::Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let mut interpreter = Interpreter::new();
+    if trace {
+        interpreter.set_trace(Some(Box::new(io::stderr())));
+    }
+    let mut entry = String::new();
+
+    loop {
+        let prompt = if entry.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // ctrl-c discards the entry being typed
+            Err(ReadlineError::Interrupted) => {
+                entry.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        entry.push_str(&line);
+        entry.push('\n');
+        if entry.trim().is_empty() {
+            entry.clear();
+            continue;
+        }
+        if is_incomplete(&entry) {
+            continue;
+        }
+
+        editor.add_history_entry(entry.trim_end())?;
+        eval(&mut interpreter, &entry, disassemble);
+        entry.clear();
+    }
+}
+
+// errors are reported and leave the interpreter usable for the next entry
+fn eval<W: Write>(interpreter: &mut Interpreter<W>, source: &str, disassemble: bool) {
+    let program = match interpreter.compile_repl(source) {
+        Ok(program) => program,
+        Err(e) => return report(source, &e),
+    };
+
+    if disassemble {
+        if let Err(e) = interpreter.disassemble(&program) {
+            eprintln!("Failed to write disassembly: {}", e);
+        }
+    }
+    if let Err(e) = interpreter.run(&program) {
+        report(source, &e);
+    }
+}
+
+// an entry is continued on the next line while it ends inside a string or
+// with parentheses or braces left open
+fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::new(source);
+    let mut depth = 0;
+
+    loop {
+        let token = scanner.scan_token();
+        match token.ty {
+            TokenType::TOKEN_LEFT_PAREN | TokenType::TOKEN_LEFT_BRACE => depth += 1,
+            TokenType::TOKEN_RIGHT_PAREN | TokenType::TOKEN_RIGHT_BRACE => depth -= 1,
+            TokenType::TOKEN_ERROR if source[token.span.start..].starts_with('"') => return true,
+            TokenType::TOKEN_EOF => return depth > 0,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_unfinished_entries() {
+        assert!(is_incomplete("fun f() {\n"));
+        assert!(is_incomplete("print (1 +\n"));
+        assert!(is_incomplete("print \"a\nb"));
+        assert!(!is_incomplete("fun f() { print \"{\"; }\n"));
+        assert!(!is_incomplete("print 1; // {\n"));
+        // too many closing brackets is a compile error, not more input
+        assert!(!is_incomplete("print 1);\n"));
+    }
+
+    #[test]
+    fn entries_share_globals_and_echo_expressions() {
+        let mut interpreter = Interpreter::with_output(Vec::new());
+        eval(&mut interpreter, "var a = 1;\n", false);
+        eval(
+            &mut interpreter,
+            "fun add(b) {\n  a + b;\n  return a + b;\n}\n",
+            false,
+        );
+        eval(&mut interpreter, "add(2)\n", false);
+        eval(&mut interpreter, "a = \"x\"; a;\n", false);
+        eval(&mut interpreter, "undefined;\n", false);
+        eval(&mut interpreter, "a\n", false);
+
+        let output = String::from_utf8(interpreter.into_output()).unwrap();
+        assert_eq!(output, "3\n\"x\"\n\"x\"\n\"x\"\n");
+    }
+
+    #[test]
+    fn nested_statements_are_not_echoed() {
+        let mut interpreter = Interpreter::with_output(Vec::new());
+        eval(
+            &mut interpreter,
+            "var i = 0; while (i < 3) i = i + 1;\n",
+            false,
+        );
+        eval(
+            &mut interpreter,
+            "if (true) \"then\"; else \"else\";\n",
+            false,
+        );
+        eval(&mut interpreter, "for (;i < 5;) { i = i + 1; }\n", false);
+        eval(&mut interpreter, "class A { m() { 1; } } A().m();\n", false);
+        eval(&mut interpreter, "i\n", false);
+
+        let output = String::from_utf8(interpreter.into_output()).unwrap();
+        assert_eq!(output, "nil\n5\n");
+    }
+}
@@ -10,6 +10,11 @@ pub struct Scanner<'a> {
     start: usize,
     current: usize,
     line: u64,
+    // column of the character at current, counted in characters
+    column: u64,
+    // position of the token being scanned
+    start_line: u64,
+    start_column: u64,
 }
 
 impl<'a> Scanner<'a> {
@@ -18,13 +23,18 @@ impl<'a> Scanner<'a> {
             source: s,
             start: 0,
             current: 0,
-            line: 0,
+            line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
         }
     }
     pub fn scan_token(&mut self) -> Token<'a> {
         self.skip_whites();
 
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
 
         if self.is_at_end() {
             return self.mk_token(TokenType::TOKEN_EOF);
@@ -75,38 +85,32 @@ impl<'a> Scanner<'a> {
                         TokenType::TOKEN_GREATER
                     })
                 }
-                '"' => self
-                    .scan_string()
-                    .unwrap_or_else(|| self.mk_error_token("Invalid string literal")),
-                c => {
-                    if c.is_digit(10) {
-                        self.scan_number()
-                    } else if c.is_alphabetic() {
-                        self.scan_identifier()
-                            .unwrap_or_else(|| self.mk_error_token("Invalid identifier"))
-                    } else {
-                        unreachable!()
-                    }
-                }
-            }).unwrap_or_else(|| self.mk_error_token("Unexpected EOF"))
+                '"' => self.scan_string().unwrap_or_else(|| {
+                    self.mk_error_token("Invalid string literal")
+                        .with_help("add a closing \" to end the string")
+                }),
+                c if c.is_ascii_digit() => self.scan_number(),
+                c if c.is_alphabetic() => self
+                    .scan_identifier()
+                    .unwrap_or_else(|| self.mk_error_token("Invalid identifier")),
+                _ => self
+                    .mk_error_token("Unexpected character")
+                    .with_help("remove this character"),
+            })
+            .unwrap_or_else(|| self.mk_error_token("Unexpected EOF"))
     }
     fn skip_whites(&mut self) {
         loop {
             match self.peek() {
                 Some('\n') => {
-                    self.line += 1;
                     self.advance();
+                    self.new_line();
                 }
-                Some('/') => {
+                Some('/') if self.peek_next() == Some('/') => {
                     self.skip_line_comment();
-                    return;
                 }
-                Some(c) => {
-                    if c.is_whitespace() {
-                        self.advance();
-                    } else {
-                        return;
-                    }
+                Some(c) if c.is_whitespace() => {
+                    self.advance();
                 }
                 _ => return,
             }
@@ -115,15 +119,15 @@ impl<'a> Scanner<'a> {
     fn scan_string(&mut self) -> Option<Token<'a>> {
         loop {
             let c = self.peek()?;
-            if c == '\n' {
-                self.line += 1;
-            }
             if c == '"' {
                 // The closing "
                 self.advance();
                 return Some(self.mk_token(TokenType::TOKEN_STRING));
             }
             self.advance();
+            if c == '\n' {
+                self.new_line();
+            }
         }
     }
     #[inline]
@@ -145,13 +149,13 @@ impl<'a> Scanner<'a> {
         }
     }
     fn scan_number(&mut self) -> Token<'a> {
-        self.consume_while(|c| c.is_digit(10));
+        self.consume_while(|c| c.is_ascii_digit());
 
         let d = self.peek();
         // consume dot
         if d == Some('.') {
             self.advance();
-            self.consume_while(|c| c.is_digit(10));
+            self.consume_while(|c| c.is_ascii_digit());
         }
 
         self.mk_token(TokenType::TOKEN_NUMBER)
@@ -205,14 +209,31 @@ impl<'a> Scanner<'a> {
     fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.column = 1;
+    }
+
+    fn span(&self) -> Span {
+        Span {
+            start: self.start,
+            end: self.current,
+        }
+    }
     fn mk_error_token(&self, msg: &'static str) -> Token<'a> {
-        Token::new(TokenType::TOKEN_ERROR, msg, self.line)
+        Token::new(
+            TokenType::TOKEN_ERROR,
+            msg,
+            self.span(),
+            self.start_line,
+            self.start_column,
+        )
     }
     fn mk_token(&self, ty: TokenType) -> Token<'a> {
         let raw = self.source.get(self.start..self.current);
 
         if let Some(raw) = raw {
-            Token::new(ty, raw, self.line)
+            Token::new(ty, raw, self.span(), self.start_line, self.start_column)
         } else {
             self.mk_error_token("Malformed utf8")
         }
@@ -234,6 +255,7 @@ impl<'a> Scanner<'a> {
         let c = self.peek()?;
 
         self.current += c.len_utf8();
+        self.column += 1;
 
         Some(c)
     }
@@ -242,7 +264,7 @@ impl<'a> Scanner<'a> {
             return false;
         }
 
-        let matched = self.peek().map_or(false, |c| c == expected);
+        let matched = self.peek() == Some(expected);
 
         if matched {
             self.advance();
@@ -251,3 +273,63 @@ impl<'a> Scanner<'a> {
         matched
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn positions(source: &str) -> Vec<(u64, u64)> {
+        let mut scanner = Scanner::new(source);
+        let mut positions = Vec::new();
+        loop {
+            let token = scanner.scan_token();
+            if token.ty == TokenType::TOKEN_EOF {
+                return positions;
+            }
+            positions.push((token.line, token.column));
+        }
+    }
+
+    #[test]
+    fn columns_count_characters_from_the_line_start() {
+        assert_eq!(
+            positions("var é = \"a\nb\"; x\n  // c\n\ty;"),
+            [
+                (1, 1),
+                (1, 5),
+                (1, 7),
+                (1, 9),
+                (2, 3),
+                (2, 5),
+                (4, 2),
+                (4, 3)
+            ]
+        );
+    }
+
+    fn token_types(source: &str) -> Vec<TokenType> {
+        let mut scanner = Scanner::new(source);
+        let mut types = Vec::new();
+        loop {
+            let token = scanner.scan_token();
+            types.push(token.ty);
+            if token.ty == TokenType::TOKEN_EOF {
+                return types;
+            }
+        }
+    }
+
+    #[test]
+    fn slash_is_division_unless_it_starts_a_comment() {
+        assert_eq!(
+            token_types("8 / 2 // half\n1"),
+            [
+                TokenType::TOKEN_NUMBER,
+                TokenType::TOKEN_SLASH,
+                TokenType::TOKEN_NUMBER,
+                TokenType::TOKEN_NUMBER,
+                TokenType::TOKEN_EOF
+            ]
+        );
+    }
+}
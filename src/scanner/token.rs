@@ -50,16 +50,44 @@ pub enum TokenType {
     TOKEN_EOF,
 }
 
+// byte offsets into the source, end is exclusive
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub struct Token<'a> {
     pub ty: TokenType,
+    // the lexeme, or the message for error tokens
     pub raw: &'a str,
+    pub span: Span,
+    // 1-based position of the first character of the token
     pub line: u64,
+    pub column: u64,
+    // how to fix the error of an error token
+    pub help: Option<&'static str>,
 }
 
 impl<'a> Token<'a> {
-    pub fn new(ty: TokenType, raw: &'a str, line: u64) -> Self {
-        Token { ty, raw, line }
+    pub fn new(ty: TokenType, raw: &'a str, span: Span, line: u64, column: u64) -> Self {
+        Token {
+            ty,
+            raw,
+            span,
+            line,
+            column,
+            help: None,
+        }
+    }
+    // identifier the compiler refers to without it appearing in the source
+    pub fn synthetic(raw: &'a str, line: u64) -> Self {
+        Token::new(TokenType::TOKEN_IDENTIFIER, raw, Span::default(), line, 0)
+    }
+    pub fn with_help(mut self, help: &'static str) -> Self {
+        self.help = Some(help);
+        self
     }
     pub fn identifier_equal(&self, other: &Token<'a>) -> bool {
         self.raw == other.raw
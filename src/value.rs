@@ -1,15 +1,14 @@
-use std::borrow::Cow;
-use std::convert::{From, Into};
+use std::convert::From;
 use std::fmt;
 use std::mem;
 use std::ops::{Add, Div, Mul, Neg, Sub};
 
-use object::{Obj, ObjString};
+use gc::Heap;
+use object::Obj;
 
 pub type ValueOwned = Value<Obj>;
-pub type ValueRef<'a> = Value<Cow<'a, Obj>>;
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub enum Value<O> {
     Nil,
     Number(f64),
@@ -17,23 +16,15 @@ pub enum Value<O> {
     Object(O),
 }
 
-impl<'a> From<&'a ValueOwned> for ValueRef<'a> {
-    fn from(v: &'a ValueOwned) -> ValueRef<'a> {
-        match v {
-            Value::Nil => Value::Nil,
-            Value::Number(ref n) => Value::Number(*n),
-            Value::Bool(ref n) => Value::Bool(*n),
-            Value::Object(ref o) => Value::Object(Cow::Borrowed(o)),
-        }
-    }
-}
-
-impl<O> PartialEq for Value<O> {
+// values of different types are never equal. Objects are equal when they
+// are the same object, strings are interned so this compares their content.
+impl<O: PartialEq> PartialEq for Value<O> {
     fn eq(&self, other: &Value<O>) -> bool {
         match (self, other) {
             (&Value::Nil, &Value::Nil) => true,
             (&Value::Number(a), &Value::Number(b)) => a == b,
             (&Value::Bool(a), &Value::Bool(b)) => a == b,
+            (Value::Object(a), Value::Object(b)) => a == b,
             _ => false,
         }
     }
@@ -56,21 +47,45 @@ impl<O> Value<O> {
         }
     }
     pub fn is_falsy(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+}
+
+impl ValueOwned {
+    pub fn display<'h>(&'h self, heap: &'h Heap) -> DisplayValue<'h> {
+        DisplayValue { value: self, heap }
+    }
+    // name of the type as seen by scripts
+    pub fn type_name(&self) -> &'static str {
         match self {
-            Value::Nil => true,
-            Value::Bool(false) => true,
-            _ => false,
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Number(_) => "number",
+            Value::Object(Obj::String(_)) => "string",
+            Value::Object(Obj::Class(_)) => "class",
+            Value::Object(Obj::Instance(_)) => "instance",
+            Value::Object(Obj::List(_)) => "list",
+            Value::Object(Obj::BoundMethod(_))
+            | Value::Object(Obj::Closure(_))
+            | Value::Object(Obj::Function(_))
+            | Value::Object(Obj::Native(_)) => "function",
         }
     }
 }
 
-impl<O: fmt::Display> fmt::Display for Value<O> {
+// formats a value whose objects live in heap
+pub struct DisplayValue<'h> {
+    value: &'h ValueOwned,
+    heap: &'h Heap,
+}
+
+impl<'h> fmt::Display for DisplayValue<'h> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
+        match self.value {
             Value::Number(n) => write!(f, "{}", n),
             Value::Bool(b) => write!(f, "{}", b),
             Value::Nil => write!(f, "nil"),
-            Value::Object(ref o) => write!(f, "{}", o),
+            Value::Object(ref o) => o.fmt_in(self.heap, f),
         }
     }
 }
@@ -86,12 +101,6 @@ impl<O> From<bool> for Value<O> {
         Value::Bool(b)
     }
 }
-impl<'a> From<&'a str> for ValueOwned {
-    fn from(s: &'a str) -> ValueOwned {
-        let obj_s = ObjString::new(s);
-        Value::Object(Obj::String(Box::new(obj_s)))
-    }
-}
 
 impl<O> Neg for Value<O> {
     type Output = Option<Value<O>>;
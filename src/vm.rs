@@ -1,239 +1,1798 @@
-use std::borrow::Cow;
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::io::Write;
-use std::ops::Deref;
+use std::error;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::{Rc, Weak};
 use std::result;
 
-use arraydeque::ArrayDeque;
-
-use chunk::Chunk;
 use common::*;
-use object::{Obj, ObjString};
-use value::{Value, ValueRef};
+use compiler::CompileError;
+use gc::{Gc, Heap};
+use native::{NativeContext, NativeError, NativeFn, DEFAULT_NATIVES};
+use object::{
+    Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjNative, ObjString,
+    ObjUpvalue,
+};
+use value::{Value, ValueOwned};
 
+#[derive(Debug)]
 pub enum InterpretError {
-    CompileError,
-    RuntimeError,
+    // every error found in the source, the program was not run
+    CompileError(Vec<CompileError>),
+    RuntimeError(RuntimeError),
+}
+
+impl fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InterpretError::CompileError(ref errors) => {
+                for (i, e) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", e)?;
+                }
+                Ok(())
+            }
+            InterpretError::RuntimeError(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for InterpretError {}
+
+// error raised by a running program, with the calls active when it occurred
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    // line of the instruction that failed
+    pub line: u64,
+    // innermost call first, the last frame is the script
+    pub trace: Vec<TraceFrame>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    // None for the top level script
+    pub function: Option<String>,
+    pub line: u64,
 }
 
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in self.trace.iter() {
+            match frame.function {
+                Some(ref name) => write!(f, "\n[line {}] in {}()", frame.line, name)?,
+                None => write!(f, "\n[line {}] in script", frame.line)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl error::Error for RuntimeError {}
+
 pub type Result<T> = result::Result<T, InterpretError>;
 
-pub struct VM<'a, W> {
-    chunk: &'a Chunk,
+// default limit on the number of values on the stack. Every call keeps at
+// least its callee there, so this also bounds the depth of calls.
+pub const STACK_MAX: usize = 16 * 1024;
+// natives calling back into the program nest on the host stack
+pub const NATIVE_DEPTH_MAX: usize = 64;
+
+struct CallFrame {
+    closure: Gc<ObjClosure>,
+    // function of the closure, saves a heap lookup per instruction
+    function: Gc<ObjFunction>,
     ip: usize,
-    stack: ArrayDeque<[ValueRef<'a>; 256]>,
-    globals: HashMap<&'a str, ValueRef<'a>>,
+    // stack index of the frame's slot 0
+    slots: usize,
+}
+
+pub struct VM<W> {
+    heap: Heap,
+    frames: Vec<CallFrame>,
+    stack: Vec<ValueOwned>,
+    // pushing past this many values is a stack overflow
+    stack_limit: usize,
+    globals: HashMap<Gc<ObjString>, ValueOwned>,
+    // interned "init", looked up on every class call
+    init_string: Gc<ObjString>,
+    // upvalues still pointing into the stack, ordered by stack slot
+    open_upvalues: Vec<Gc<ObjUpvalue>>,
     output: W,
+    // receives the stack and the next instruction before each one runs
+    trace: Option<Box<dyn Write>>,
+    // objects held by the host, alive while their pin is
+    pins: Vec<(Obj, Weak<()>)>,
+    // results of calls made by the natives being run
+    native_roots: Vec<ValueOwned>,
+    // number of natives being run, each waiting on the ones it called
+    native_depth: usize,
 }
-impl<'a, W> VM<'a, W> {
-    pub fn new(chunk: &'a Chunk, output: W) -> Self {
-        VM {
-            chunk,
+impl<W> VM<W> {
+    pub fn new(output: W) -> Self {
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+
+        let mut vm = VM {
+            heap,
+            init_string,
             output,
-            ip: 0,
+            frames: Vec::new(),
             globals: HashMap::new(),
-            stack: ArrayDeque::new(),
+            stack: Vec::new(),
+            stack_limit: STACK_MAX,
+            open_upvalues: Vec::new(),
+            trace: None,
+            pins: Vec::new(),
+            native_roots: Vec::new(),
+            native_depth: 0,
+        };
+
+        vm.define_natives(&DEFAULT_NATIVES);
+
+        vm
+    }
+    // makes a host function callable from scripts as a global, replacing
+    // any global of the same name
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let native = self.heap.alloc(ObjNative::new(name, arity, function));
+        let name = self.heap.intern(name);
+        self.globals
+            .insert(name, Value::Object(Obj::Native(native)));
+    }
+    // defines each name, arity and function as with define_native
+    pub fn define_natives(&mut self, natives: &[(&str, usize, NativeFn)]) {
+        for &(name, arity, function) in natives.iter() {
+            self.define_native(name, arity, function);
         }
     }
+    // limits how many values the stack can hold and with it how deep calls
+    // can nest, a program exceeding it fails with a stack overflow
+    pub fn set_stack_limit(&mut self, limit: usize) {
+        self.stack_limit = limit;
+    }
+    // traces execution to the given writer, kept apart from the output so
+    // that the two never interleave. None turns tracing off.
+    pub fn set_trace(&mut self, trace: Option<Box<dyn Write>>) {
+        self.trace = trace;
+    }
+    // where print writes to
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+    pub fn output_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+    pub fn into_output(self) -> W {
+        self.output
+    }
+    pub fn global(&self, name: &str) -> Option<ValueOwned> {
+        let name = self.heap.find_string(name)?;
+        self.globals.get(&name).cloned()
+    }
+    pub fn set_global(&mut self, name: &str, value: ValueOwned) {
+        let name = self.heap.intern(name);
+        self.globals.insert(name, value);
+    }
+    // keeps obj from being collected until the returned pin is dropped,
+    // for objects the host holds on to between runs
+    pub fn pin(&mut self, obj: Obj) -> Rc<()> {
+        let pin = Rc::new(());
+        self.pins.push((obj, Rc::downgrade(&pin)));
+
+        pin
+    }
+    // functions run by this vm must be compiled into its heap
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
 }
 
-impl<'a, W: Write> VM<'a, W> {
-    pub fn disassemble(&mut self) {
-        self.chunk.disassemble(&mut self.output);
+impl<W: Write> VM<W> {
+    pub fn disassemble(&mut self, function: Gc<ObjFunction>) -> io::Result<()> {
+        self.heap
+            .get(function)
+            .disassemble(&self.heap, &mut self.output)
+    }
+    pub fn interpret(&mut self, function: Gc<ObjFunction>) -> Result<()> {
+        let closure = self.heap.alloc(ObjClosure::new(function, Vec::new()));
+        let result = self
+            .push_value(Value::Object(Obj::Closure(closure)))
+            .and_then(|_| self.call_closure(closure, 0))
+            .and_then(|_| self.run(0))
+            // the script always returns nil
+            .and_then(|_| self.pop_value())
+            .map(|_| ());
+        // a failed program leaves the vm ready for the next one, globals
+        // defined before the error are kept
+        if result.is_err() {
+            self.reset_stack();
+        }
+
+        result
     }
-    pub fn interpret(&mut self) -> Result<()> {
-        self.run()
+    // calls a function, method, class or native with args and returns its
+    // result. Natives use this to call back into the running program, the
+    // host to call functions of a program that already ran.
+    pub fn call(&mut self, callee: &ValueOwned, args: &[ValueOwned]) -> Result<ValueOwned> {
+        let frames = self.frames.len();
+        let slots = self.stack.len();
+
+        let result = self.call_and_run(callee, args, frames);
+        match result {
+            // keeps the result alive until the native asking for it returns
+            Ok(ref value) if self.native_depth > 0 => self.native_roots.push(value.clone()),
+            Ok(_) => {}
+            // unwinds the calls that failed, the caller may carry on
+            Err(_) => {
+                self.close_upvalues(slots);
+                self.frames.truncate(frames);
+                self.stack.truncate(slots);
+            }
+        }
+
+        result
     }
-    fn run(&mut self) -> Result<()> {
+    fn call_and_run(
+        &mut self,
+        callee: &ValueOwned,
+        args: &[ValueOwned],
+        frames: usize,
+    ) -> Result<ValueOwned> {
+        self.push_value(callee.clone())?;
+        for arg in args.iter() {
+            self.push_value(arg.clone())?;
+        }
+        self.call_value(callee.clone(), args.len())?;
+
+        // natives and classes without an initializer are done already
+        if self.frames.len() > frames {
+            self.run(frames)?;
+        }
+
+        self.pop_value()
+    }
+    fn reset_stack(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.native_roots.clear();
+    }
+    // runs until the number of frames drops back to base
+    fn run(&mut self, base: usize) -> Result<()> {
         loop {
+            // between instructions every live object is reachable from the
+            // roots, temporaries of an instruction are never collected
+            if self.heap.should_collect() {
+                self.collect_garbage();
+            }
+            if self.trace.is_some() {
+                self.trace_instruction();
+            }
+
             let instr: u8 = self.read_byte();
             match instr {
-                OP_PRINT => {
-                    if let Some(v) = self.pop_value() {
-                        self.print_value(&v);
+                OP_RETURN => {
+                    let result = self.pop_value()?;
+                    let frame = self.frames.pop().expect("No active call frame");
+
+                    self.close_upvalues(frame.slots);
+                    // discard the callee, arguments and locals of the frame
+                    self.stack.truncate(frame.slots);
+                    self.push_value(result)?;
+
+                    if self.frames.len() == base {
+                        return Ok(());
                     }
                 }
+                OP_CALL => {
+                    let arg_count = self.read_byte() as usize;
+                    let callee = self.peek(arg_count)?;
+                    self.call_value(callee, arg_count)?;
+                }
+                OP_PRINT => {
+                    let value = self.pop_value()?;
+                    self.print_value(&value);
+                }
                 OP_NIL => {
-                    self.push_value(Value::Nil);
+                    self.push_value(Value::Nil)?;
                 }
                 OP_TRUE => {
-                    self.push_value(Value::from(true));
+                    self.push_value(Value::from(true))?;
                 }
                 OP_FALSE => {
-                    self.push_value(Value::from(false));
+                    self.push_value(Value::from(false))?;
                 }
                 OP_CONSTANT => {
                     let constant = self.read_constant();
-                    self.push_value(constant);
+                    self.push_value(constant)?;
+                }
+                OP_CONSTANT_LONG => {
+                    let constant = self.read_constant_long();
+                    self.push_value(constant)?;
                 }
                 OP_POP => {
-                    let _ = self.pop_value().ok_or(InterpretError::RuntimeError)?;
-                }
-                OP_GET_GLOBAL => {
-                    let name = self.read_string().ok_or(InterpretError::RuntimeError)?;
-                    let mut value;
-                    {
-                        let v = self.globals.get(name).ok_or(InterpretError::RuntimeError)?;
-                        value = v.clone();
+                    self.pop_value()?;
+                }
+                OP_GET_LOCAL => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    let value = self.get_slot(slot)?;
+                    self.push_value(value)?;
+                }
+                OP_SET_LOCAL => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    let value = self.peek(0)?;
+                    self.set_slot(slot, value)?;
+                }
+                OP_GET_UPVALUE => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.heap.get(self.frame().closure).upvalues[slot];
+                    let value = match *self.heap.get(upvalue) {
+                        ObjUpvalue::Open(index) => self.get_slot(index)?,
+                        ObjUpvalue::Closed(ref value) => value.clone(),
+                    };
+                    self.push_value(value)?;
+                }
+                OP_SET_UPVALUE => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.heap.get(self.frame().closure).upvalues[slot];
+                    let value = self.peek(0)?;
+                    match *self.heap.get(upvalue) {
+                        ObjUpvalue::Open(index) => self.set_slot(index, value)?,
+                        ObjUpvalue::Closed(_) => {
+                            *self.heap.get_mut(upvalue) = ObjUpvalue::Closed(value)
+                        }
+                    };
+                }
+                OP_CLOSE_UPVALUE => {
+                    let last = self.stack.len() - 1;
+                    self.close_upvalues(last);
+                    self.pop_value()?;
+                }
+                OP_CLOSURE | OP_CLOSURE_LONG => {
+                    let function = match self.read_operand(instr) {
+                        Value::Object(Obj::Function(function)) => function,
+                        _ => return Err(self.runtime_error("Expected a function constant")),
+                    };
+
+                    let upvalue_count = self.heap.get(function).upvalue_count;
+                    let mut upvalues = Vec::with_capacity(upvalue_count);
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        let upvalue = if is_local {
+                            let slot = self.frame().slots + index;
+                            self.capture_upvalue(slot)
+                        } else {
+                            self.heap.get(self.frame().closure).upvalues[index]
+                        };
+                        upvalues.push(upvalue);
+                    }
+
+                    let closure = self.heap.alloc(ObjClosure::new(function, upvalues));
+                    self.push_value(Value::Object(Obj::Closure(closure)))?;
+                }
+                OP_CLASS | OP_CLASS_LONG => {
+                    let name = self.read_string(instr)?;
+                    let class = ObjClass::new(self.heap.get(name).clone());
+                    let class = self.heap.alloc(class);
+                    self.push_value(Value::Object(Obj::Class(class)))?;
+                }
+                OP_GET_PROPERTY | OP_GET_PROPERTY_LONG => {
+                    let instance = match self.peek(0)? {
+                        Value::Object(Obj::Instance(instance)) => instance,
+                        _ => return Err(self.runtime_error("Only instances have properties")),
+                    };
+                    let name = self.read_string(instr)?;
+
+                    // fields shadow methods
+                    let instance = self.heap.get(instance);
+                    if let Some(value) = instance.fields.get(&name).cloned() {
+                        self.pop_value()?;
+                        self.push_value(value)?;
+                    } else {
+                        let class = instance.class;
+                        self.bind_method(class, name)?;
+                    }
+                }
+                OP_SET_PROPERTY | OP_SET_PROPERTY_LONG => {
+                    let instance = match self.peek(1)? {
+                        Value::Object(Obj::Instance(instance)) => instance,
+                        _ => return Err(self.runtime_error("Only instances have fields")),
+                    };
+                    let name = self.read_string(instr)?;
+
+                    let value = self.pop_value()?;
+                    let fields = &mut self.heap.get_mut(instance).fields;
+                    if fields.insert(name, value.clone()).is_none() {
+                        self.heap.resized(instance);
+                    }
+
+                    self.pop_value()?;
+                    self.push_value(value)?;
+                }
+                OP_METHOD | OP_METHOD_LONG => {
+                    let name = self.read_string(instr)?;
+                    let method = match self.peek(0)? {
+                        Value::Object(Obj::Closure(method)) => method,
+                        _ => return Err(self.runtime_error("Expected a method closure")),
+                    };
+                    match self.peek(1)? {
+                        Value::Object(Obj::Class(class)) => {
+                            self.heap.get_mut(class).methods.insert(name, method);
+                            self.heap.resized(class);
+                        }
+                        _ => return Err(self.runtime_error("Expected a class")),
+                    }
+                    self.pop_value()?;
+                }
+                OP_INVOKE | OP_INVOKE_LONG => {
+                    let name = self.read_string(instr)?;
+                    let arg_count = self.read_byte() as usize;
+                    self.invoke(name, arg_count)?;
+                }
+                OP_INHERIT => {
+                    let superclass = match self.peek(1)? {
+                        Value::Object(Obj::Class(superclass)) => superclass,
+                        _ => return Err(self.runtime_error("Superclass must be a class")),
+                    };
+                    match self.peek(0)? {
+                        Value::Object(Obj::Class(subclass)) => {
+                            // copy down inherited methods, methods declared
+                            // in the subclass body override them afterwards
+                            let methods = self.heap.get(superclass).methods.clone();
+                            self.heap.get_mut(subclass).methods.extend(methods);
+                            self.heap.resized(subclass);
+                        }
+                        _ => return Err(self.runtime_error("Expected a class")),
+                    }
+                    self.pop_value()?;
+                }
+                OP_GET_SUPER | OP_GET_SUPER_LONG => {
+                    let name = self.read_string(instr)?;
+                    let superclass = match self.pop_value()? {
+                        Value::Object(Obj::Class(superclass)) => superclass,
+                        _ => return Err(self.runtime_error("Superclass must be a class")),
+                    };
+                    self.bind_method(superclass, name)?;
+                }
+                OP_SUPER_INVOKE | OP_SUPER_INVOKE_LONG => {
+                    let name = self.read_string(instr)?;
+                    let arg_count = self.read_byte() as usize;
+                    let superclass = match self.pop_value()? {
+                        Value::Object(Obj::Class(superclass)) => superclass,
+                        _ => return Err(self.runtime_error("Superclass must be a class")),
+                    };
+                    self.invoke_from_class(superclass, name, arg_count)?;
+                }
+                OP_JUMP => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset as usize;
+                }
+                OP_LOOP => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip -= offset as usize;
+                }
+                OP_JUMP_IF_FALSE => {
+                    let offset = self.read_short();
+                    let condition = self.peek(0)?;
+                    if condition.is_falsy() {
+                        self.frame_mut().ip += offset as usize;
                     }
-                    self.push_value(value);
                 }
-                OP_DEFINE_GLOBAL => {
-                    let name = self.read_string().ok_or(InterpretError::RuntimeError)?;
-                    let value = self.peek(0).ok_or(InterpretError::RuntimeError)?;
+                OP_GET_GLOBAL | OP_GET_GLOBAL_LONG => {
+                    let name = self.read_string(instr)?;
+                    let value = match self.globals.get(&name) {
+                        Some(value) => value.clone(),
+                        None => return Err(self.undefined_variable(name)),
+                    };
+                    self.push_value(value)?;
+                }
+                OP_DEFINE_GLOBAL | OP_DEFINE_GLOBAL_LONG => {
+                    let name = self.read_string(instr)?;
+                    let value = self.peek(0)?;
 
                     self.globals.insert(name, value);
-                    let _ = self.pop_value().ok_or(InterpretError::RuntimeError)?;
+                    self.pop_value()?;
                 }
-                OP_SET_GLOBAL => {
-                    let name = self.read_string().ok_or(InterpretError::RuntimeError)?;
-                    let value = self.peek(0).ok_or(InterpretError::RuntimeError)?;
+                OP_SET_GLOBAL | OP_SET_GLOBAL_LONG => {
+                    let name = self.read_string(instr)?;
+                    let value = self.peek(0)?;
                     // is new key
                     if self.globals.insert(name, value).is_none() {
-                        return Err(InterpretError::RuntimeError);
+                        self.globals.remove(&name);
+                        return Err(self.undefined_variable(name));
                     }
                 }
                 OP_NEGATE => {
-                    let value = self.pop_value().ok_or(InterpretError::RuntimeError)?;
-                    let neg_value = (-value).ok_or(InterpretError::RuntimeError)?;
-                    self.push_value(neg_value);
+                    let value = self.pop_value()?;
+                    let neg_value = match -value {
+                        Some(neg_value) => neg_value,
+                        None => return Err(self.runtime_error("Operand must be a number")),
+                    };
+                    self.push_value(neg_value)?;
                 }
                 OP_NOT => {
-                    let value = self.pop_value().ok_or(InterpretError::RuntimeError)?;
+                    let value = self.pop_value()?;
                     let value = Value::from(value.is_falsy());
-                    self.push_value(value);
+                    self.push_value(value)?;
                 }
                 OP_EQUAL => {
-                    if let Some(value) = self.binary_op(|a, b| Some(Value::from(a == b))) {
-                        self.push_value(value);
-                    } else {
-                        return Err(InterpretError::RuntimeError);
-                    }
+                    self.binary_op(|a, b| Some(Value::from(a == b)))?;
                 }
                 OP_GREATER => {
-                    if let Some(value) = self.binary_op(|a, b| {
-                        let a = a.into_number()?;
-                        let b = b.into_number()?;
-
-                        Some(Value::from(a > b))
-                    }) {
-                        self.push_value(value);
-                    } else {
-                        return Err(InterpretError::RuntimeError);
-                    }
+                    self.comparison(|ordering| ordering == Ordering::Greater)?;
                 }
                 OP_LESS => {
-                    if let Some(value) = self.binary_op(|a, b| {
-                        let a = a.into_number()?;
-                        let b = b.into_number()?;
-
-                        Some(Value::from(a < b))
-                    }) {
-                        self.push_value(value);
-                    } else {
-                        return Err(InterpretError::RuntimeError);
-                    }
+                    self.comparison(|ordering| ordering == Ordering::Less)?;
+                }
+                OP_GREATER_EQUAL => {
+                    self.comparison(|ordering| ordering != Ordering::Less)?;
+                }
+                OP_LESS_EQUAL => {
+                    self.comparison(|ordering| ordering != Ordering::Greater)?;
                 }
                 OP_ADD => {
-                    let b = self.peek(0).ok_or(InterpretError::RuntimeError)?;
-                    let a = self.peek(1).ok_or(InterpretError::RuntimeError)?;
+                    let b = self.peek(0)?;
+                    let a = self.peek(1)?;
                     match (b, a) {
                         (Value::Number(_), Value::Number(_)) => {
-                            let value = self
-                                .binary_op(|a, b| a + b)
-                                .ok_or(InterpretError::RuntimeError)?;
-                            self.push_value(value);
+                            self.binary_op(|a, b| a + b)?;
+                        }
+                        (Value::Object(Obj::String(b)), Value::Object(Obj::String(a))) => {
+                            let mut s: String = self.heap.get(a)[..].to_owned();
+                            s.push_str(&self.heap.get(b)[..]);
+                            let value = Value::Object(Obj::String(self.heap.intern(&s)));
+
+                            self.pop_value()?;
+                            self.pop_value()?;
+                            self.push_value(value)?;
+                        }
+                        _ => {
+                            return Err(
+                                self.runtime_error("Operands must be two numbers or two strings")
+                            )
                         }
-                        (Value::Object(b), Value::Object(a)) => match (&*b, &*a) {
-                            (Obj::String(ref b), Obj::String(ref a)) => {
-                                let mut s: String = (&**a).deref().to_owned();
-                                s.push_str(b);
-                                let obj_s = ObjString::new(s);
-                                let value = Value::Object(Cow::Owned(Obj::String(Box::new(obj_s))));
-
-                                self.push_value(value);
-                            }
-                            _ => return Err(InterpretError::RuntimeError),
-                        },
-                        _ => return Err(InterpretError::RuntimeError),
                     }
                 }
                 OP_SUBTRACT => {
-                    if let Some(value) = self.binary_op(|a, b| a - b) {
-                        self.push_value(value);
-                    } else {
-                        return Err(InterpretError::RuntimeError);
-                    }
+                    self.binary_op(|a, b| a - b)?;
                 }
                 OP_MULTIPLY => {
-                    if let Some(value) = self.binary_op(|a, b| a * b) {
-                        self.push_value(value);
-                    } else {
-                        return Err(InterpretError::RuntimeError);
-                    }
+                    self.binary_op(|a, b| a * b)?;
                 }
                 OP_DIVIDE => {
-                    if let Some(value) = self.binary_op(|a, b| a / b) {
-                        self.push_value(value);
-                    } else {
-                        return Err(InterpretError::RuntimeError);
-                    }
+                    self.binary_op(|a, b| a / b)?;
                 }
-                _ => return Err(InterpretError::CompileError),
+                _ => return Err(self.runtime_error(format!("Unknown opcode {}", instr))),
             }
         }
     }
 
+    fn call_value(&mut self, callee: ValueOwned, arg_count: usize) -> Result<()> {
+        match callee {
+            Value::Object(Obj::Closure(closure)) => self.call_closure(closure, arg_count),
+            Value::Object(Obj::Native(native)) => {
+                let native = self.heap.get(native);
+                if arg_count != native.arity {
+                    let message = arity_mismatch(native.arity, arg_count);
+                    return Err(self.runtime_error(message));
+                }
+                let function = native.function;
+
+                let first_arg = self.stack.len() - arg_count;
+                let args: Vec<ValueOwned> = self.stack.iter().skip(first_arg).cloned().collect();
+                if self.native_depth == NATIVE_DEPTH_MAX {
+                    return Err(self.runtime_error("Stack overflow"));
+                }
+
+                let roots = self.native_roots.len();
+                self.native_depth += 1;
+                let result = function(self, &args);
+                self.native_depth -= 1;
+                self.native_roots.truncate(roots);
+
+                let result = match result {
+                    Ok(result) => result,
+                    Err(NativeError::Message(message)) => return Err(self.runtime_error(message)),
+                    Err(NativeError::Call(e)) => return Err(InterpretError::RuntimeError(e)),
+                };
+
+                // discard the callee and arguments
+                for _ in 0..=arg_count {
+                    self.pop_value()?;
+                }
+                self.push_value(result)?;
+
+                Ok(())
+            }
+            Value::Object(Obj::BoundMethod(bound)) => {
+                let bound = self.heap.get(bound);
+                let receiver = bound.receiver.clone();
+                let method = bound.method;
+
+                // the receiver takes the callee slot, where methods expect this
+                self.set_peek(arg_count, receiver)?;
+                self.call_closure(method, arg_count)
+            }
+            Value::Object(Obj::Class(class)) => {
+                // the new instance replaces the class in the callee slot
+                let initializer = self.heap.get(class).find_method(self.init_string);
+                let instance = self.heap.alloc(ObjInstance::new(class));
+                self.set_peek(arg_count, Value::Object(Obj::Instance(instance)))?;
+
+                match initializer {
+                    Some(initializer) => self.call_closure(initializer, arg_count),
+                    None if arg_count != 0 => Err(self.runtime_error(arity_mismatch(0, arg_count))),
+                    None => Ok(()),
+                }
+            }
+            _ => Err(self.runtime_error("Can only call functions and classes")),
+        }
+    }
+    fn invoke(&mut self, name: Gc<ObjString>, arg_count: usize) -> Result<()> {
+        let instance = match self.peek(arg_count)? {
+            Value::Object(Obj::Instance(instance)) => instance,
+            _ => return Err(self.runtime_error("Only instances have methods")),
+        };
+
+        // a field holding a function is called like any other value
+        let instance = self.heap.get(instance);
+        if let Some(value) = instance.fields.get(&name).cloned() {
+            self.set_peek(arg_count, value.clone())?;
+            return self.call_value(value, arg_count);
+        }
+
+        let class = instance.class;
+        self.invoke_from_class(class, name, arg_count)
+    }
+    fn invoke_from_class(
+        &mut self,
+        class: Gc<ObjClass>,
+        name: Gc<ObjString>,
+        arg_count: usize,
+    ) -> Result<()> {
+        let method = match self.heap.get(class).find_method(name) {
+            Some(method) => method,
+            None => return Err(self.undefined_property(name)),
+        };
+        self.call_closure(method, arg_count)
+    }
+    // replaces the instance on top of the stack with its method bound to it
+    fn bind_method(&mut self, class: Gc<ObjClass>, name: Gc<ObjString>) -> Result<()> {
+        let method = match self.heap.get(class).find_method(name) {
+            Some(method) => method,
+            None => return Err(self.undefined_property(name)),
+        };
+        let receiver = self.pop_value()?;
+
+        let bound = self.heap.alloc(ObjBoundMethod::new(receiver, method));
+        self.push_value(Value::Object(Obj::BoundMethod(bound)))?;
+
+        Ok(())
+    }
+    fn call_closure(&mut self, closure: Gc<ObjClosure>, arg_count: usize) -> Result<()> {
+        let function = self.heap.get(closure).function;
+        let arity = self.heap.get(function).arity;
+        if arg_count != arity {
+            return Err(self.runtime_error(arity_mismatch(arity, arg_count)));
+        }
+        let slots = self.stack.len() - arg_count - 1;
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slots,
+        });
+
+        Ok(())
+    }
+
+    // reuses the open upvalue for a stack slot if one exists so that all
+    // closures capturing a variable share it
+    fn capture_upvalue(&mut self, slot: usize) -> Gc<ObjUpvalue> {
+        let heap = &self.heap;
+        let position = self
+            .open_upvalues
+            .iter()
+            .position(|&upvalue| heap.get(upvalue).open_slot() >= Some(slot))
+            .unwrap_or(self.open_upvalues.len());
+
+        if let Some(&upvalue) = self.open_upvalues.get(position) {
+            if heap.get(upvalue).open_slot() == Some(slot) {
+                return upvalue;
+            }
+        }
+
+        let upvalue = self.heap.alloc(ObjUpvalue::Open(slot));
+        self.open_upvalues.insert(position, upvalue);
+
+        upvalue
+    }
+    // moves values of stack slots at or above last into their upvalues
+    fn close_upvalues(&mut self, last: usize) {
+        let stack = &self.stack;
+        let heap = &mut self.heap;
+
+        self.open_upvalues.retain(|&upvalue| {
+            let slot = match heap.get(upvalue).open_slot() {
+                Some(slot) if slot >= last => slot,
+                _ => return true,
+            };
+
+            let value = stack.get(slot).cloned().unwrap_or(Value::Nil);
+            *heap.get_mut(upvalue) = ObjUpvalue::Closed(value);
+
+            false
+        });
+    }
+
+    // marks everything the running program can still reach and frees the
+    // rest, constants are reached through the functions of the frames
+    fn collect_garbage(&mut self) {
+        for value in self.stack.iter() {
+            self.heap.mark_value(value);
+        }
+        for frame in self.frames.iter() {
+            self.heap.mark(frame.closure);
+        }
+        for &upvalue in self.open_upvalues.iter() {
+            self.heap.mark(upvalue);
+        }
+        for (&name, value) in self.globals.iter() {
+            self.heap.mark(name);
+            self.heap.mark_value(value);
+        }
+        self.heap.mark(self.init_string);
+        self.pins.retain(|(_, pin)| pin.strong_count() > 0);
+        for &(obj, _) in self.pins.iter() {
+            self.heap.mark_object(obj);
+        }
+        for value in self.native_roots.iter() {
+            self.heap.mark_value(value);
+        }
+
+        self.heap.collect();
+    }
+
+    // writes the stack followed by the instruction about to run
+    fn trace_instruction(&mut self) {
+        let trace = match self.trace {
+            Some(ref mut trace) => trace,
+            None => return,
+        };
+        let frame = self.frames.last().expect("No active call frame");
+        let stack = &self.stack;
+        let heap = &self.heap;
+
+        let mut write_trace = || -> io::Result<()> {
+            write!(trace, "          ")?;
+            for value in stack.iter() {
+                write!(trace, "[ {} ]", value.display(heap))?;
+            }
+            writeln!(trace)?;
+
+            heap.get(frame.function)
+                .chunk
+                .trace_instruction(frame.ip, heap, trace)?;
+            Ok(())
+        };
+        // a broken trace stream is not an error of the running program
+        let _ = write_trace();
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("No active call frame")
+    }
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("No active call frame")
+    }
+
     fn read_byte(&mut self) -> u8 {
-        let byte = self.chunk.read_byte(self.ip);
+        let frame = self.frames.last_mut().expect("No active call frame");
+        let byte = self.heap.get(frame.function).chunk.read_byte(frame.ip);
 
-        self.ip += 1;
+        frame.ip += 1;
 
         byte
     }
-    fn read_constant(&mut self) -> ValueRef<'a> {
-        let constant = self.chunk.read_constant(self.ip);
+    fn read_short(&mut self) -> u16 {
+        let frame = self.frames.last_mut().expect("No active call frame");
+        let short = self.heap.get(frame.function).chunk.read_short(frame.ip);
+
+        frame.ip += 2;
+
+        short
+    }
+    fn read_constant(&mut self) -> ValueOwned {
+        let frame = self.frames.last_mut().expect("No active call frame");
+        let constant = self.heap.get(frame.function).chunk.read_constant(frame.ip);
+
+        frame.ip += 1;
+
+        constant
+    }
+    fn read_constant_long(&mut self) -> ValueOwned {
+        let frame = self.frames.last_mut().expect("No active call frame");
+        let constant = self
+            .heap
+            .get(frame.function)
+            .chunk
+            .read_constant_long(frame.ip);
 
-        self.ip += 1;
+        frame.ip += 3;
 
         constant
     }
-    fn read_string(&mut self) -> Option<&'a str> {
-        let value: ValueRef<'a> = self.read_constant();
-        match value {
-            Value::Object(Cow::Borrowed(o)) => match o {
-                Obj::String(o) => Some((&**o).deref()),
-                _ => None,
-            },
-            _ => None,
+    // constant operand of an instruction in its short or long form
+    fn read_operand(&mut self, instr: u8) -> ValueOwned {
+        if is_long_form(instr) {
+            self.read_constant_long()
+        } else {
+            self.read_constant()
+        }
+    }
+    fn read_string(&mut self, instr: u8) -> Result<Gc<ObjString>> {
+        match self.read_operand(instr) {
+            Value::Object(Obj::String(s)) => Ok(s),
+            _ => Err(self.runtime_error("Expected a string constant")),
         }
     }
 
-    fn push_value(&mut self, v: ValueRef<'a>) {
-        // panic on overflow
-        self.stack.push_back(v).unwrap();
+    fn push_value(&mut self, v: ValueOwned) -> Result<()> {
+        if self.stack.len() >= self.stack_limit {
+            return Err(self.runtime_error("Stack overflow"));
+        }
+        self.stack.push(v);
+
+        Ok(())
     }
-    fn pop_value(&mut self) -> Option<ValueRef<'a>> {
-        self.stack.pop_back()
+    fn pop_value(&mut self) -> Result<ValueOwned> {
+        match self.stack.pop() {
+            Some(value) => Ok(value),
+            None => Err(self.runtime_error("Stack underflow")),
+        }
+    }
+    fn peek(&self, distance: usize) -> Result<ValueOwned> {
+        match self.stack.len().checked_sub(distance + 1) {
+            Some(index) => self.get_slot(index),
+            None => Err(self.runtime_error("Stack underflow")),
+        }
     }
-    fn peek(&self, distance: usize) -> Option<ValueRef<'a>> {
-        let n = self.stack.len();
-        let index = n - 1 - distance;
 
-        self.stack.get(index).cloned()
+    fn set_peek(&mut self, distance: usize, v: ValueOwned) -> Result<()> {
+        match self.stack.len().checked_sub(distance + 1) {
+            Some(index) => self.set_slot(index, v),
+            None => Err(self.runtime_error("Stack underflow")),
+        }
     }
 
-    fn print_value(&mut self, v: &ValueRef) {
-        writeln!(self.output, "{}", v);
+    fn get_slot(&self, slot: usize) -> Result<ValueOwned> {
+        match self.stack.get(slot) {
+            Some(value) => Ok(value.clone()),
+            None => Err(self.runtime_error("Stack slot out of range")),
+        }
+    }
+    fn set_slot(&mut self, slot: usize, v: ValueOwned) -> Result<()> {
+        match self.stack.get_mut(slot) {
+            Some(value) => {
+                *value = v;
+                Ok(())
+            }
+            None => Err(self.runtime_error("Stack slot out of range")),
+        }
     }
 
-    fn binary_op<F>(&mut self, f: F) -> Option<ValueRef<'a>>
+    // error for the instruction being executed, traced through every
+    // active call
+    fn runtime_error<S: Into<String>>(&self, message: S) -> InterpretError {
+        let trace: Vec<TraceFrame> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = self.heap.get(frame.function);
+                TraceFrame {
+                    function: function.name.as_ref().map(|name| name[..].to_owned()),
+                    // ip is already past the failing instruction
+                    line: function.chunk.line_no(frame.ip.saturating_sub(1)),
+                }
+            })
+            .collect();
+        let line = trace.first().map_or(0, |frame| frame.line);
+
+        InterpretError::RuntimeError(RuntimeError {
+            message: message.into(),
+            line,
+            trace,
+        })
+    }
+    fn undefined_variable(&self, name: Gc<ObjString>) -> InterpretError {
+        self.runtime_error(format!("Undefined variable '{}'", &self.heap.get(name)[..]))
+    }
+    fn undefined_property(&self, name: Gc<ObjString>) -> InterpretError {
+        self.runtime_error(format!("Undefined property '{}'", &self.heap.get(name)[..]))
+    }
+
+    fn print_value(&mut self, v: &ValueOwned) {
+        // a broken output stream is not an error of the running program
+        let _ = writeln!(self.output, "{}", v.display(&self.heap));
+    }
+
+    // numbers compare by value and strings lexicographically, comparisons
+    // involving NaN are false
+    fn comparison<F>(&mut self, f: F) -> Result<()>
     where
-        F: for<'b> Fn(ValueRef<'b>, ValueRef<'b>) -> Option<ValueRef<'b>>,
+        F: Fn(Ordering) -> bool,
     {
+        let b = self.pop_value()?;
         let a = self.pop_value()?;
+
+        let result = match (a, b) {
+            (Value::Number(a), Value::Number(b)) => a.partial_cmp(&b).is_some_and(f),
+            (Value::Object(Obj::String(a)), Value::Object(Obj::String(b))) => {
+                f(self.heap.get(a)[..].cmp(&self.heap.get(b)[..]))
+            }
+            _ => return Err(self.runtime_error("Operands must be two numbers or two strings")),
+        };
+        self.push_value(Value::from(result))?;
+
+        Ok(())
+    }
+
+    // arithmetic operators yield None for operands that are not numbers
+    fn binary_op<F>(&mut self, f: F) -> Result<()>
+    where
+        F: Fn(ValueOwned, ValueOwned) -> Option<ValueOwned>,
+    {
         let b = self.pop_value()?;
+        let a = self.pop_value()?;
+
+        match f(a, b) {
+            Some(value) => {
+                self.push_value(value)?;
+                Ok(())
+            }
+            None => Err(self.runtime_error("Operands must be numbers")),
+        }
+    }
+}
+
+impl<W: Write> NativeContext for VM<W> {
+    fn heap(&self) -> &Heap {
+        &self.heap
+    }
+    fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+    fn call(
+        &mut self,
+        callee: &ValueOwned,
+        args: &[ValueOwned],
+    ) -> result::Result<ValueOwned, RuntimeError> {
+        match VM::call(self, callee, args) {
+            Ok(value) => Ok(value),
+            Err(InterpretError::RuntimeError(e)) => Err(e),
+            // calls only run code that compiled already
+            Err(e) => unreachable!("{}", e),
+        }
+    }
+}
+
+fn arity_mismatch(arity: usize, arg_count: usize) -> String {
+    format!("Expected {} arguments but got {}", arity, arg_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compiler::compile;
+    use native::LIST_NATIVES;
+    use std::cell::RefCell;
+    use std::mem;
+    use std::rc::Rc;
+
+    fn interpret(vm: &mut VM<Vec<u8>>, source: &str) -> Result<String> {
+        let function = compile(source, vm.heap_mut()).expect("Failed to compile");
+        vm.interpret(function)?;
+
+        Ok(String::from_utf8(vm.output.clone()).unwrap())
+    }
+
+    fn run(source: &str) -> Result<String> {
+        interpret(&mut VM::new(Vec::new()), source)
+    }
+
+    // runs source collecting garbage before every instruction that follows
+    // an allocation
+    fn run_stressed(source: &str) -> (VM<Vec<u8>>, String) {
+        let mut vm = VM::new(Vec::new());
+        vm.heap_mut().set_stress(true);
+
+        let output = interpret(&mut vm, source).expect("Failed to run");
+        (vm, output)
+    }
+
+    #[test]
+    fn equality() {
+        let output = run("
+            print \"a\" == \"a\";
+            print \"a\" + \"b\" == \"ab\";
+            print \"a\" != \"b\";
+            print 1 == 1;
+            print nil == nil;
+            print true == true;
+            class A {}
+            var a = A();
+            print a == a;
+            print A() == A();
+            print clock == clock;
+            ")
+        .unwrap();
+
+        assert_eq!(
+            output,
+            "true\ntrue\ntrue\ntrue\ntrue\ntrue\ntrue\nfalse\ntrue\n"
+        );
+    }
+
+    #[test]
+    fn equality_of_mixed_types_is_false() {
+        let output = run("
+            print 1 == \"1\";
+            print nil == false;
+            print 0 == false;
+            print \"nil\" == nil;
+            print 0/0 == 0/0;
+            ")
+        .unwrap();
+
+        assert_eq!(output, "false\nfalse\nfalse\nfalse\nfalse\n");
+    }
+
+    #[test]
+    fn comparison() {
+        let output = run("
+            print 1 < 2;
+            print 2 > 1;
+            print \"a\" < \"b\";
+            print \"ab\" > \"a\";
+            print \"B\" < \"a\";
+            print \"a\" < \"a\";
+            print \"a\" <= \"a\";
+            print \"b\" >= \"a\";
+            print 2 <= 1;
+            print 0/0 < 1;
+            print 0/0 <= 1;
+            print 0/0 >= 1;
+            print 0/0 >= 0/0;
+            ")
+        .unwrap();
+
+        assert_eq!(
+            output,
+            "true\ntrue\ntrue\ntrue\ntrue\nfalse\ntrue\ntrue\nfalse\nfalse\nfalse\nfalse\nfalse\n"
+        );
+    }
+
+    #[test]
+    fn comparison_of_mixed_types_is_an_error() {
+        assert!(run("print 1 < \"2\";").is_err());
+        assert!(run("print \"a\" > nil;").is_err());
+        assert!(run("print true < false;").is_err());
+    }
+
+    #[test]
+    fn blocks_scope_locals() {
+        let output = run("
+            var a = \"global\";
+            {
+                var a = \"outer\";
+                {
+                    var a = \"inner\";
+                    print a;
+                }
+                print a;
+                a = \"assigned\";
+                print a;
+            }
+            print a;
+            ")
+        .unwrap();
+
+        assert_eq!(output, "\"inner\"\n\"outer\"\n\"assigned\"\n\"global\"\n");
+    }
+
+    #[test]
+    fn if_else_takes_one_branch() {
+        let output = run("
+            if (true) print \"then\"; else print \"else\";
+            if (nil) print \"then\"; else print \"else\";
+            if (0) print \"zero is true\";
+            if (false) print \"skipped\";
+            var a = 1;
+            if (a == 1) { var b = 2; print a + b; }
+            ")
+        .unwrap();
+
+        assert_eq!(output, "\"then\"\n\"else\"\n\"zero is true\"\n3\n");
+    }
+
+    #[test]
+    fn jumps_disassemble_with_their_target() {
+        let mut vm = VM::new(Vec::new());
+        let function = compile("if (true) print 1; else print 2;", vm.heap_mut()).unwrap();
+        vm.disassemble(function).unwrap();
+
+        let output = String::from_utf8(vm.output.clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[2], "   | OP_JUMP_IF_FALSE 0001 -> 0011");
+        assert_eq!(lines[6], "   | OP_JUMP          0008 -> 0015");
+    }
+
+    #[test]
+    fn loops_repeat_their_body() {
+        let output = run("
+            var i = 0;
+            while (i < 3) { print i; i = i + 1; }
+            for (var j = 0; j < 3; j = j + 1) print j * 10;
+            var k = 5;
+            for (; k > 3;) k = k - 1;
+            print k;
+            ")
+        .unwrap();
+
+        assert_eq!(output, "0\n1\n2\n0\n10\n20\n3\n");
+    }
+
+    #[test]
+    fn for_initializer_is_scoped_to_the_loop() {
+        let output = run("
+            var i = \"global\";
+            for (var i = 0; i < 2; i = i + 1) {}
+            print i;
+            ")
+        .unwrap();
+        assert_eq!(output, "\"global\"\n");
+
+        assert_eq!(
+            runtime_error("for (var j = 0; j < 1; j = j + 1) {} print j;").message,
+            "Undefined variable 'j'"
+        );
+    }
+
+    #[test]
+    fn and_or_short_circuit() {
+        let output = run("
+            fun fail() { print \"evaluated\"; return true; }
+            print nil and fail();
+            print 1 and 2;
+            print \"a\" or fail();
+            print false or nil;
+            print false or \"b\";
+            print true and false or \"c\";
+            ")
+        .unwrap();
+
+        assert_eq!(output, "nil\n2\n\"a\"\nnil\n\"b\"\n\"c\"\n");
+    }
+
+    #[test]
+    fn functions_call_and_return() {
+        let output = run("
+            fun fib(n) {
+                if (n < 2) return n;
+                return fib(n - 1) + fib(n - 2);
+            }
+            fun nothing() {}
+            var f = fib;
+            print f(10);
+            print nothing();
+            print fib;
+            ")
+        .unwrap();
+
+        assert_eq!(output, "55\nnil\n<fn fib>\n");
+    }
+
+    #[test]
+    fn calls_check_arity() {
+        assert_eq!(
+            runtime_error("fun f(a, b) {} f(1);").message,
+            "Expected 2 arguments but got 1"
+        );
+        assert_eq!(
+            runtime_error("fun f() {} f(1, 2);").message,
+            "Expected 0 arguments but got 2"
+        );
+    }
+
+    #[test]
+    fn closures_share_captured_variables() {
+        let output = run("
+            var get;
+            var set;
+            fun pair() {
+                var value = \"initial\";
+                fun g() { return value; }
+                fun s(v) { value = v; }
+                get = g;
+                set = s;
+                print get();
+                set(\"open\");
+                print value;
+            }
+            pair();
+            // the variable outlives its frame and is still shared
+            print get();
+            set(\"closed\");
+            print get();
+            ")
+        .unwrap();
+
+        assert_eq!(output, "\"initial\"\n\"open\"\n\"open\"\n\"closed\"\n");
+    }
+
+    #[test]
+    fn closures_capture_through_enclosing_functions() {
+        let output = run("
+            fun outer() {
+                var x = 1;
+                fun middle() {
+                    fun inner() { x = x + 1; return x; }
+                    return inner;
+                }
+                return middle();
+            }
+            var inner = outer();
+            print inner();
+            print inner();
+            print outer()();
+            ")
+        .unwrap();
+
+        assert_eq!(output, "2\n3\n2\n");
+    }
+
+    #[test]
+    fn closures_created_in_loops_capture_each_iteration() {
+        let output = run("
+            var first;
+            var second;
+            for (var i = 1; i <= 2; i = i + 1) {
+                var j = i;
+                fun f() { return j; }
+                if (i == 1) first = f; else second = f;
+            }
+            print first();
+            print second();
+
+            {
+                var k = 0;
+                var fs;
+                while (k < 2) {
+                    k = k + 1;
+                    var copy = k;
+                    fun g() { return copy; }
+                    if (k == 1) fs = g;
+                }
+                print fs();
+            }
+            ")
+        .unwrap();
+
+        assert_eq!(output, "1\n2\n1\n");
+    }
+
+    #[test]
+    fn instances_have_fields() {
+        let output = run("
+            class Point {}
+            var p = Point();
+            p.x = 1;
+            p.y = p.x + 1;
+            var q = Point();
+            q.x = \"other\";
+            print p.x + p.y;
+            print q.x;
+            print Point;
+            print p;
+            ")
+        .unwrap();
+
+        assert_eq!(output, "3\n\"other\"\nPoint\nPoint instance\n");
+    }
+
+    #[test]
+    fn property_errors() {
+        assert_eq!(
+            runtime_error("class A {} A().missing;").message,
+            "Undefined property 'missing'"
+        );
+        assert_eq!(
+            runtime_error("var s = \"str\"; s.x;").message,
+            "Only instances have properties"
+        );
+        assert_eq!(
+            runtime_error("nil.x = 1;").message,
+            "Only instances have fields"
+        );
+    }
+
+    #[test]
+    fn methods_bind_this() {
+        let output = run("
+            class Counter {
+                init(start) { this.count = start; }
+                increment() { this.count = this.count + 1; return this.count; }
+            }
+            var c = Counter(10);
+            var increment = c.increment;
+            print c.increment();
+            print increment();
+            print increment;
+            // fields shadow methods
+            fun replaced() { return \"field\"; }
+            c.increment = replaced;
+            print c.increment();
+            ")
+        .unwrap();
+
+        assert_eq!(output, "11\n12\n<fn increment>\n\"field\"\n");
+    }
+
+    #[test]
+    fn initializers_return_the_instance() {
+        let output = run("
+            class A {
+                init(x) {
+                    this.x = x;
+                    if (x > 1) return;
+                    this.small = true;
+                }
+            }
+            var a = A(1);
+            var same = a.init(2);
+            print same == a;
+            print a.x;
+            print A(3).x;
+            ")
+        .unwrap();
+
+        assert_eq!(output, "true\n2\n3\n");
+    }
+
+    #[test]
+    fn initializers_check_arity() {
+        assert_eq!(
+            runtime_error("class A { init(a) {} } A();").message,
+            "Expected 1 arguments but got 0"
+        );
+        assert_eq!(
+            runtime_error("class A {} A(1);").message,
+            "Expected 0 arguments but got 1"
+        );
+    }
+
+    #[test]
+    fn subclasses_inherit_and_override_methods() {
+        let output = run("
+            class A {
+                name() { return \"A\"; }
+                greet() { return \"hello from \" + this.name(); }
+            }
+            class B < A {
+                name() { return \"B\"; }
+                greet() { return super.greet() + \" via B\"; }
+            }
+            class C < B {}
+            print A().greet();
+            print C().greet();
+            class D < A {
+                name() { return \"D\"; }
+                get() { return super.name; }
+            }
+            var method = D().get();
+            print method();
+            ")
+        .unwrap();
+
+        assert_eq!(output, "\"hello from A\"\n\"hello from B via B\"\n\"A\"\n");
+    }
+
+    #[test]
+    fn super_works_inside_nested_closures() {
+        let output = run("
+            class Base {
+                say() { return \"base \" + this.tag; }
+            }
+            class Derived < Base {
+                init() { this.tag = \"derived\"; }
+                say() {
+                    fun later() { return super.say(); }
+                    return later;
+                }
+            }
+            var later = Derived().say();
+            print later();
+            ")
+        .unwrap();
+
+        assert_eq!(output, "\"base derived\"\n");
+    }
+
+    #[test]
+    fn superclass_must_be_a_class() {
+        assert_eq!(
+            runtime_error("var A = 1; class B < A {}").message,
+            "Superclass must be a class"
+        );
+    }
+
+    #[test]
+    fn default_natives() {
+        let output = run("
+            print len(\"héllo\");
+            print str(12.5) + str(nil);
+            print num(\" 42 \") + num(1);
+            print type(1) + type(\"\") + type(nil) + type(clock);
+            print clock() > 0;
+            print clock;
+            ")
+        .unwrap();
+
+        assert_eq!(
+            output,
+            "5\n\"12.5nil\"\n43\n\"numberstringnilfunction\"\ntrue\n<native fn clock>\n"
+        );
+        assert_eq!(
+            runtime_error("num(\"x\");").message,
+            "num() cannot convert \"x\" to a number"
+        );
+        assert_eq!(
+            runtime_error("len(\"a\", \"b\");").message,
+            "Expected 1 arguments but got 2"
+        );
+    }
+
+    #[test]
+    fn list_natives() {
+        // only defined on request
+        assert_eq!(
+            runtime_error("list();").message,
+            "Undefined variable 'list'"
+        );
+
+        let mut vm = VM::new(Vec::new());
+        vm.define_natives(&LIST_NATIVES);
+        let output = interpret(
+            &mut vm,
+            "
+            var l = push(push(list(), 1), \"two\");
+            push(l, nil);
+            set(l, 0, get(l, 1));
+            print len(l);
+            print get(l, 0);
+            print type(l);
+            print l;
+            ",
+        )
+        .unwrap();
+        assert_eq!(output, "3\n\"two\"\n\"list\"\n<list of 3>\n");
+
+        let mut message = |source| match interpret(&mut vm, source) {
+            Err(InterpretError::RuntimeError(e)) => e.message,
+            other => panic!("Expected a runtime error, got {:?}", other),
+        };
+        assert_eq!(
+            message("get(list(), 0);"),
+            "get() index 0 is out of range for a list of length 0"
+        );
+        assert_eq!(
+            message("var l = push(list(), 1); set(l, 1.5, 2);"),
+            "set() index 1.5 is out of range for a list of length 1"
+        );
+        assert_eq!(
+            message("class List {} push(List(), 1);"),
+            "push() expects a list"
+        );
+        assert_eq!(message("list().length = 1;"), "Only instances have fields");
+    }
+
+    #[test]
+    fn growing_objects_count_toward_collection() {
+        let mut vm = VM::new(Vec::new());
+        vm.define_natives(&LIST_NATIVES);
+        let source = "
+            class Box {}
+            var box = Box();
+            box.a = 1; box.b = 2; box.c = 3;
+            var l = list();
+            for (var i = 0; i < 10000; i = i + 1) push(l, i);
+            ";
+        interpret(&mut vm, source).unwrap();
+
+        let grown = 3 * mem::size_of::<(Gc<ObjString>, ValueOwned)>()
+            + 10000 * mem::size_of::<ValueOwned>();
+        assert!(vm.heap().bytes_allocated() > grown);
+    }
+
+    fn add(
+        _ctx: &mut dyn NativeContext,
+        args: &[ValueOwned],
+    ) -> result::Result<ValueOwned, NativeError> {
+        match (&args[0], &args[1]) {
+            (&Value::Number(a), &Value::Number(b)) => Ok(Value::from(a + b)),
+            _ => Err("add() expects two numbers".into()),
+        }
+    }
+
+    #[test]
+    fn host_natives_are_globals() {
+        let mut vm = VM::new(Vec::new());
+        vm.define_native("add", 2, add);
+        assert_eq!(interpret(&mut vm, "print add(1, 2);").unwrap(), "3\n");
+
+        match interpret(&mut vm, "fun f() { add(1, nil); }\nf();") {
+            Err(InterpretError::RuntimeError(e)) => {
+                assert_eq!(e.message, "add() expects two numbers");
+                assert_eq!(e.trace.len(), 2);
+            }
+            other => panic!("Expected a runtime error, got {:?}", other),
+        }
+    }
+
+    fn runtime_error(source: &str) -> RuntimeError {
+        match run(source) {
+            Err(InterpretError::RuntimeError(e)) => e,
+            other => panic!("Expected a runtime error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn runtime_errors_have_messages() {
+        let message = |source| runtime_error(source).message;
+
+        assert_eq!(message("-\"a\";"), "Operand must be a number");
+        assert_eq!(message("1 - nil;"), "Operands must be numbers");
+        assert_eq!(
+            message("1 + \"a\";"),
+            "Operands must be two numbers or two strings"
+        );
+        assert_eq!(message("print x;"), "Undefined variable 'x'");
+        assert_eq!(message("x = 1;"), "Undefined variable 'x'");
+        assert_eq!(message("class A {} A().x;"), "Undefined property 'x'");
+        assert_eq!(message("true.x = 2;"), "Only instances have fields");
+        assert_eq!(message("\"a\"();"), "Can only call functions and classes");
+        assert_eq!(
+            message("fun f(a) {} f();"),
+            "Expected 1 arguments but got 0"
+        );
+        assert_eq!(message("len(1);"), "len() expects a string or a list");
+        assert_eq!(message("fun f() { f(); } f();"), "Stack overflow");
+    }
+
+    #[test]
+    fn runtime_errors_trace_active_calls() {
+        let error = runtime_error(
+            "class A {
+                method() {
+                    return nil + 1;
+                }
+            }
+            fun f() {
+                return A().method();
+            }
+            f();",
+        );
+
+        assert_eq!(error.line, 3);
+        let trace: Vec<_> = error
+            .trace
+            .iter()
+            .map(|frame| (frame.function.as_ref().map(|name| &name[..]), frame.line))
+            .collect();
+        assert_eq!(trace, [(Some("method"), 3), (Some("f"), 7), (None, 9)]);
+        assert_eq!(
+            error.to_string(),
+            "Operands must be two numbers or two strings\n\
+             [line 3] in method()\n\
+             [line 7] in f()\n\
+             [line 9] in script"
+        );
+    }
+
+    #[test]
+    fn vm_is_reusable_after_a_runtime_error() {
+        let mut vm = VM::new(Vec::new());
+        assert!(interpret(&mut vm, "var a = 1; fun f() { var b = 2; nil(); } f();").is_err());
+        assert!(vm.stack.is_empty());
+        assert!(vm.frames.is_empty());
+
+        // globals defined before the error survive
+        assert_eq!(interpret(&mut vm, "print a;").unwrap(), "1\n");
+    }
+
+    // an expression keeping depth + 1 values on the stack at its deepest
+    fn nested_sum(depth: usize) -> String {
+        format!("print {}1{};", "1 + (".repeat(depth), ")".repeat(depth))
+    }
+
+    #[test]
+    fn stack_grows_as_needed() {
+        assert_eq!(run(&nested_sum(400)).unwrap(), "401\n");
+    }
+
+    #[test]
+    fn stack_overflow_is_a_runtime_error() {
+        let mut vm = VM::new(Vec::new());
+        vm.set_stack_limit(16);
+
+        match interpret(&mut vm, &nested_sum(20)) {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "Stack overflow"),
+            other => panic!("Expected a stack overflow, got {:?}", other),
+        }
+        assert_eq!(interpret(&mut vm, &nested_sum(10)).unwrap(), "11\n");
+    }
+
+    #[test]
+    fn stack_limit_bounds_call_depth() {
+        let source = "
+            fun depth(n) {
+                if (n == 0) return 0;
+                return depth(n - 1) + 1;
+            }
+            print depth(1000);
+            ";
+        assert_eq!(run(source).unwrap(), "1000\n");
+
+        let mut vm = VM::new(Vec::new());
+        vm.set_stack_limit(500);
+        match interpret(&mut vm, source) {
+            Err(InterpretError::RuntimeError(e)) => assert_eq!(e.message, "Stack overflow"),
+            other => panic!("Expected a stack overflow, got {:?}", other),
+        }
+        vm.set_stack_limit(5000);
+        assert_eq!(interpret(&mut vm, source).unwrap(), "1000\n");
+    }
+
+    // trace writer whose contents stay readable after being handed to the vm
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_is_written_apart_from_output() {
+        let trace = SharedBuffer::default();
+        let mut vm = VM::new(Vec::new());
+        vm.set_trace(Some(Box::new(trace.clone())));
+
+        let output = interpret(&mut vm, "print 1 + 2;").unwrap();
+        assert_eq!(output, "3\n");
+
+        let trace = String::from_utf8(trace.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines[0].trim_end(), "          [ <script> ]");
+        assert_eq!(lines[1], "0000 0001 OP_CONSTANT      0000 1");
+        assert_eq!(lines[4].trim_end(), "          [ <script> ][ 1 ][ 2 ]");
+        assert_eq!(lines[5], "0004 0001 OP_ADD");
+        assert_eq!(lines.len(), 12);
+    }
+
+    #[test]
+    fn long_constant_operands() {
+        // every global name and number below takes a constant slot
+        let mut source = String::new();
+        for i in 0..300 {
+            source.push_str(&format!("var g{} = {};\n", i, i));
+        }
+        source.push_str("g299 = g299 + 1;\nprint g0 + g299;\nprint 1000.5;\n");
+
+        assert_eq!(run(&source).unwrap(), "300\n1000.5\n");
+    }
+
+    #[test]
+    fn long_operands_for_functions_classes_and_properties() {
+        let mut source = String::new();
+        for i in 0..300 {
+            source.push_str(&format!("var g{} = {};\n", i, i));
+        }
+        // fills the constants of the methods using super
+        let filler: String = (0..300).map(|i| format!("{};", i)).collect();
+        source.push_str(&format!(
+            "
+            fun f() {{ return \"f\"; }}
+            class A {{
+                init(x) {{ this.x = x; }}
+                get() {{ return this.x; }}
+            }}
+            class B < A {{
+                get() {{ {0} return super.get() + 1; }}
+                bound() {{ {0} return super.get; }}
+            }}
+            var b = B(g299);
+            b.y = b.get();
+            print f();
+            print b.y;
+            print b.bound()();
+            ",
+            filler
+        ));
+
+        assert_eq!(run(&source).unwrap(), "\"f\"\n300\n299\n");
+
+        let mut vm = VM::new(Vec::new());
+        let function = compile(&source, vm.heap_mut()).unwrap();
+        vm.disassemble(function).unwrap();
+        let output = String::from_utf8(vm.output.clone()).unwrap();
+        for op in [
+            "OP_CLOSURE_LONG",
+            "OP_CLASS_LONG",
+            "OP_METHOD_LONG",
+            "OP_GET_PROPERTY_LONG",
+            "OP_SET_PROPERTY_LONG",
+            "OP_INVOKE_LONG",
+            "OP_SUPER_INVOKE_LONG",
+            "OP_GET_SUPER_LONG",
+        ]
+        .iter()
+        {
+            assert!(output.contains(op), "{} was not emitted", op);
+        }
+    }
+
+    #[test]
+    fn stress_keeps_reachable_objects() {
+        let (_, output) = run_stressed(
+            "
+            fun counter() {
+                var count = 0;
+                fun increment() { count = count + 1; return count; }
+                return increment;
+            }
+            class Box { init(value) { this.value = value; } }
+            var next = counter();
+            var box = Box(\"a\");
+            for (var i = 0; i < 3; i = i + 1) {
+                box.value = box.value + \"b\";
+                next();
+            }
+            print box.value;
+            print next();
+            ",
+        );
+
+        assert_eq!(output, "\"abbb\"\n4\n");
+    }
+
+    #[test]
+    fn stress_frees_unreachable_objects() {
+        let (vm, _) = run_stressed(
+            "
+            class Node {}
+            for (var i = 0; i < 100; i = i + 1) {
+                var node = Node();
+                node.name = \"node\" + str(i);
+            }
+            ",
+        );
 
-        f(b, a)
+        assert!(vm.heap().object_count() < 50);
     }
 }